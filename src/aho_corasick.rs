@@ -0,0 +1,336 @@
+//! Searching for many fixed strings at once with an Aho–Corasick automaton.
+//!
+//! The patterns are compiled into a DFA over byte classes, so each byte of
+//! the haystack costs one table lookup no matter how many patterns there
+//! are. Matches follow the same leftmost-first rule as regex alternation:
+//! the earliest match wins, and among matches starting at the same place
+//! the pattern given first wins.
+//!
+//! [`Literals`] builds on the automaton for `-i`, `-w` and `-x`: it finds
+//! candidates with it and only then checks folding boundaries and bounds.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use crate::casefold;
+use crate::regex::{self, Bounds};
+
+const DEAD: u32 = 0;
+const ROOT: u32 = 1;
+
+#[derive(Debug, Clone)]
+pub struct AhoCorasick {
+    // maps each byte to its column in `table`
+    classes: [u8; 256],
+    columns: usize,
+    // next state for each state and byte class, failures already resolved
+    table: Vec<u32>,
+    // length of the string spelled by the path to each state
+    depths: Vec<usize>,
+    // patterns ending at each state, including through failure links,
+    // as (pattern index, length) in order of preference
+    outputs: Vec<Vec<(usize, usize)>>,
+}
+
+impl AhoCorasick {
+    /// Builds the automaton. Empty patterns aren't supported and are
+    /// ignored.
+    pub fn new<P: AsRef<[u8]>>(patterns: &[P]) -> AhoCorasick {
+        let mut classes = [0u8; 256];
+        let mut columns = 1;
+        for pattern in patterns {
+            for &b in pattern.as_ref() {
+                if classes[b as usize] == 0 {
+                    classes[b as usize] = columns as u8;
+                    columns += 1;
+                }
+            }
+        }
+        // more than 255 distinct bytes can't be numbered; give each its own
+        if columns > 256 {
+            classes = std::array::from_fn(|b| b as u8);
+            columns = 256;
+        }
+
+        let mut ac = AhoCorasick {
+            classes,
+            columns,
+            table: vec![DEAD; 2 * columns],
+            depths: vec![0, 0],
+            outputs: vec![Vec::new(), Vec::new()],
+        };
+
+        // the trie, with DEAD marking missing edges
+        for (index, pattern) in patterns.iter().enumerate() {
+            let pattern = pattern.as_ref();
+            if pattern.is_empty() {
+                continue;
+            }
+            let mut state = ROOT;
+            for &b in pattern {
+                let slot = ac.slot(state, b);
+                if ac.table[slot] == DEAD {
+                    ac.table[slot] = ac.add_state(ac.depths[state as usize] + 1);
+                }
+                state = ac.table[slot];
+            }
+            ac.outputs[state as usize].push((index, pattern.len()));
+        }
+
+        // breadth first, so failure targets are always complete before use
+        let mut fail = vec![ROOT; ac.depths.len()];
+        let mut queue = VecDeque::new();
+        for class in 0..columns {
+            let slot = ROOT as usize * columns + class;
+            match ac.table[slot] {
+                DEAD => ac.table[slot] = ROOT,
+                next => queue.push_back(next),
+            }
+        }
+        while let Some(state) = queue.pop_front() {
+            let inherited = ac.outputs[fail[state as usize] as usize].clone();
+            ac.outputs[state as usize].extend(inherited);
+
+            for class in 0..columns {
+                let slot = state as usize * columns + class;
+                let fallback = ac.table[fail[state as usize] as usize * columns + class];
+                match ac.table[slot] {
+                    DEAD => ac.table[slot] = fallback,
+                    next => {
+                        fail[next as usize] = fallback;
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+        for outputs in &mut ac.outputs {
+            outputs.sort_unstable();
+        }
+
+        ac
+    }
+
+    fn add_state(&mut self, depth: usize) -> u32 {
+        let state = self.depths.len() as u32;
+        self.table.extend(std::iter::repeat_n(DEAD, self.columns));
+        self.depths.push(depth);
+        self.outputs.push(Vec::new());
+        state
+    }
+
+    fn slot(&self, state: u32, b: u8) -> usize {
+        state as usize * self.columns + self.classes[b as usize] as usize
+    }
+
+    /// Byte range of the leftmost-first match at or after `start`.
+    pub fn find_at(&self, haystack: &[u8], start: usize) -> Option<(usize, usize)> {
+        let mut state = ROOT;
+        // (start, pattern index, end) of the best match so far
+        let mut best: Option<(usize, usize, usize)> = None;
+
+        for (i, &b) in haystack.iter().enumerate().skip(start) {
+            state = self.table[self.slot(state, b)];
+            let end = i + 1;
+
+            if let Some((best_start, _, _)) = best {
+                // every later match starts at or after the current suffix
+                if end - self.depths[state as usize] > best_start {
+                    break;
+                }
+            }
+            for &(index, len) in &self.outputs[state as usize] {
+                let candidate = (end - len, index, end);
+                if best.is_none_or(|best| (candidate.0, candidate.1) < (best.0, best.1)) {
+                    best = Some(candidate);
+                }
+            }
+        }
+
+        best.map(|(start, _, end)| (start, end))
+    }
+
+    /// End of the first given pattern that occurs exactly at `start` and
+    /// whose end `accept` agrees with.
+    pub fn preferred_at(&self, haystack: &[u8], start: usize, accept: impl Fn(usize) -> bool) -> Option<usize> {
+        let mut state = ROOT;
+        // (pattern index, end) of the best match so far
+        let mut best: Option<(usize, usize)> = None;
+
+        for (i, &b) in haystack.iter().enumerate().skip(start) {
+            state = self.table[self.slot(state, b)];
+            let len = i + 1 - start;
+            // a shallower state means a failure link was followed, so no
+            // pattern starting at `start` goes on from here
+            if self.depths[state as usize] < len {
+                break;
+            }
+            for &(index, pattern_len) in &self.outputs[state as usize] {
+                if pattern_len == len && best.is_none_or(|(best, _)| index < best) && accept(i + 1) {
+                    best = Some((index, i + 1));
+                }
+            }
+        }
+
+        best.map(|(_, end)| end)
+    }
+}
+
+thread_local! {
+    // folded haystacks are built in here, so matching a line doesn't
+    // allocate
+    static FOLDED: RefCell<Folded> = RefCell::new(Folded::default());
+}
+
+/// Several non-empty fixed strings, matched within `bounds` and, with
+/// `ignore_case`, with full case folding like [`casefold::Needle`].
+#[derive(Debug, Clone)]
+pub struct Literals {
+    ac: AhoCorasick,
+    ignore_case: bool,
+    bounds: Bounds,
+}
+
+impl Literals {
+    pub fn new<P: AsRef<str>>(patterns: &[P], ignore_case: bool, bounds: Bounds) -> Literals {
+        let ac = if ignore_case {
+            let folded: Vec<String> =
+                patterns.iter().map(|pattern| pattern.as_ref().chars().flat_map(casefold::fold).collect()).collect();
+            AhoCorasick::new(&folded)
+        } else {
+            AhoCorasick::new(&patterns.iter().map(|pattern| pattern.as_ref().as_bytes()).collect::<Vec<_>>())
+        };
+        Literals { ac, ignore_case, bounds }
+    }
+
+    /// Byte range of the leftmost-first match at or after `start`.
+    pub fn find_at(&self, haystack: &[u8], start: usize) -> Option<(usize, usize)> {
+        if !self.ignore_case {
+            if self.bounds == Bounds::Any {
+                return self.ac.find_at(haystack, start);
+            }
+            return self.find_in(haystack, haystack, start, Some);
+        }
+
+        FOLDED.with(|folded| {
+            let mut folded = folded.borrow_mut();
+            folded.fold(haystack, start);
+            let Folded { text, offsets } = &*folded;
+            self.find_in(haystack, text, 0, |i| Some(offsets[i]).filter(|&pos| pos != NOT_A_BOUNDARY))
+        })
+    }
+
+    // searches `text`, which is `haystack` or its folding, and maps offsets
+    // in it back to `haystack` with `position`, which gives None where a
+    // match can't start or end
+    fn find_in(
+        &self,
+        haystack: &[u8],
+        text: &[u8],
+        mut from: usize,
+        position: impl Fn(usize) -> Option<usize>,
+    ) -> Option<(usize, usize)> {
+        while let Some((candidate, _)) = self.ac.find_at(text, from) {
+            if let Some(start) = position(candidate) {
+                let accept = |end| position(end).is_some_and(|end| self.bounds.holds(haystack, start, end));
+                if let Some(end) = self.ac.preferred_at(text, candidate, accept) {
+                    return Some((start, position(end)?));
+                }
+            }
+            from = candidate + 1;
+        }
+        None
+    }
+}
+
+// marks offsets in a folded haystack that are in the middle of a char's
+// folding
+const NOT_A_BOUNDARY: usize = usize::MAX;
+
+#[derive(Debug, Default)]
+struct Folded {
+    text: Vec<u8>,
+    // for each offset in `text` and its end, where in the haystack the char
+    // folded there starts
+    offsets: Vec<usize>,
+}
+
+impl Folded {
+    fn fold(&mut self, haystack: &[u8], start: usize) {
+        self.text.clear();
+        self.offsets.clear();
+
+        let mut pos = start;
+        while let Some((c, len)) = regex::decode(haystack, pos) {
+            match c {
+                Some(c) => {
+                    for folded in casefold::fold(c) {
+                        let mut buf = [0; 4];
+                        self.text.extend_from_slice(folded.encode_utf8(&mut buf).as_bytes());
+                    }
+                }
+                // never part of a pattern, which is valid UTF-8
+                None => self.text.extend(std::iter::repeat_n(0xff, len)),
+            }
+            self.offsets.push(pos);
+            self.offsets.resize(self.text.len(), NOT_A_BOUNDARY);
+            pos += len;
+        }
+        self.offsets.push(haystack.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(patterns: &[&str], haystack: &str) -> Option<(usize, usize)> {
+        AhoCorasick::new(patterns).find_at(haystack.as_bytes(), 0)
+    }
+
+    #[test]
+    fn leftmost_first() {
+        assert_eq!(Some((1, 4)), find(&["he", "hers", "she"], "ushers"));
+        assert_eq!(Some((2, 4)), find(&["he", "hers"], "ushers"));
+        assert_eq!(Some((2, 6)), find(&["hers", "he"], "ushers"));
+        assert_eq!(Some((0, 2)), find(&["ab", "abcd", "bc"], "abcd"));
+        assert_eq!(Some((0, 4)), find(&["abcd", "ab", "bc"], "abcd"));
+        assert_eq!(Some((1, 3)), find(&["bcd", "bc", "xyz"], "abcx"));
+        assert_eq!(None, find(&["xyz", "abd"], "abcd"));
+        assert_eq!(None, find(&[], "abcd"));
+    }
+
+    #[test]
+    fn literals_with_bounds_and_folding() {
+        let find = |patterns: &[&str], ignore_case, bounds, haystack: &str| {
+            Literals::new(patterns, ignore_case, bounds).find_at(haystack.as_bytes(), 0)
+        };
+
+        assert_eq!(Some((11, 13)), find(&["id", "name"], false, Bounds::Word, "valid; Ids id"));
+        assert_eq!(Some((7, 10)), find(&["id", "Ids"], false, Bounds::Word, "valid; Ids id"));
+        assert_eq!(Some((0, 6)), find(&["ab", "abcdef"], false, Bounds::Word, "abcdef"));
+        assert_eq!(None, find(&["ab", "cd"], false, Bounds::Line, "ab cd"));
+        assert_eq!(Some((0, 2)), find(&["cd", "ab"], false, Bounds::Line, "ab"));
+
+        assert_eq!(Some((4, 11)), find(&["rust", "straße"], true, Bounds::Any, "Die STRASSE"));
+        assert_eq!(Some((4, 11)), find(&["rust", "STRASSE"], true, Bounds::Any, "Die Straße"));
+        assert_eq!(Some((4, 9)), find(&["kiss", "go"], true, Bounds::Word, "xa; Kiſs"));
+        assert_eq!(None, find(&["stras", "go"], true, Bounds::Any, "Straße"));
+        assert_eq!(None, find(&["s", "go"], true, Bounds::Any, "ß"));
+        assert_eq!(Some((4, 6)), find(&["id", "go"], true, Bounds::Word, "ıd ID"));
+        assert_eq!(Some((7, 11)), find(&["rust", "go"], true, Bounds::Word, "trust; RUST"));
+
+        let literals = Literals::new(&["ab", "é"], true, Bounds::Any);
+        assert_eq!(Some((4, 6)), literals.find_at(b"ab \xff\xc3\x89", 1));
+    }
+
+    #[test]
+    fn find_at_offsets() {
+        let ac = AhoCorasick::new(&["id", "name"]);
+        let haystack = b"id, name, \xffid";
+
+        assert_eq!(Some((0, 2)), ac.find_at(haystack, 0));
+        assert_eq!(Some((4, 8)), ac.find_at(haystack, 1));
+        assert_eq!(Some((11, 13)), ac.find_at(haystack, 8));
+        assert_eq!(None, ac.find_at(haystack, 13));
+    }
+}
@@ -0,0 +1,291 @@
+//! Command line parsing.
+//!
+//! Every option is described once in [`OPTIONS`], which drives both the
+//! parser and the `--help` text. Short flags can be combined (`-in`),
+//! values can be attached (`-A3`, `--after-context=3`) or passed as the
+//! next argument, and `--` ends option parsing.
+
+use std::fmt;
+
+pub struct Opt {
+    pub short: Option<char>,
+    pub long: &'static str,
+    /// Name of the value the option takes, if it takes one.
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+}
+
+const fn flag(short: Option<char>, long: &'static str, help: &'static str) -> Opt {
+    Opt { short, long, value: None, help }
+}
+
+const fn valued(short: Option<char>, long: &'static str, value: &'static str, help: &'static str) -> Opt {
+    Opt { short, long, value: Some(value), help }
+}
+
+pub const OPTIONS: &[Opt] = &[
+    flag(Some('i'), "ignore-case", "Search case-insensitively"),
+    flag(Some('s'), "case-sensitive", "Search case-sensitively, overriding -i, -S and IGNORE_CASE"),
+    flag(Some('S'), "smart-case", "Search case-insensitively unless the query has an uppercase letter"),
+    valued(Some('e'), "pattern", "PATTERN", "Search for PATTERN; can be given more than once"),
+    valued(Some('f'), "file", "FILE", "Search for each line of FILE as a pattern"),
+    flag(Some('v'), "invert-match", "Select lines that don't match the query"),
+    flag(Some('w'), "word-regexp", "Only match whole words"),
+    flag(Some('x'), "line-regexp", "Only match whole lines"),
+    flag(Some('E'), "regexp", "Treat the query as a regular expression (default)"),
+    flag(Some('F'), "fixed-strings", "Treat the query as a literal string"),
+    flag(Some('o'), "only-matching", "Print each match on its own line instead of whole lines"),
+    flag(Some('n'), "line-number", "Prefix each line with its file name and line number"),
+    flag(None, "column", "Prefix each line with the column of its first match"),
+    flag(Some('b'), "byte-offset", "Prefix each line with the byte offset of its first match"),
+    valued(Some('r'), "replace", "TEXT", "Print matched lines with every match replaced by TEXT"),
+    flag(None, "in-place", "Rewrite files with every match replaced instead of printing them"),
+    flag(None, "diff", "Print a unified diff of what --replace would change, for `patch -p1`"),
+    valued(None, "backup-suffix", "SUFFIX", "With --in-place, keep the original of each file as FILE+SUFFIX"),
+    flag(None, "preserve-case", "Match the case of each replaced text: rust->go, Rust->Go, RUST->GO"),
+    valued(Some('A'), "after-context", "NUM", "Print NUM lines after each match"),
+    valued(Some('B'), "before-context", "NUM", "Print NUM lines before each match"),
+    valued(Some('C'), "context", "NUM", "Print NUM lines before and after each match"),
+    flag(Some('a'), "text", "Search binary files as if they were text"),
+    valued(None, "binary-files", "TYPE", "How to treat binary files: binary, without-match or text"),
+    flag(Some('c'), "count", "Print the number of matched lines in each file"),
+    flag(None, "count-matches", "Print the number of matches in each file"),
+    flag(Some('l'), "files-with-matches", "Print only the names of files with a match"),
+    flag(Some('L'), "files-without-match", "Print only the names of files without a match"),
+    flag(None, "json", "Print results as JSON Lines, one event per line"),
+    valued(None, "color", "WHEN", "When to color output: auto, always or never"),
+    flag(None, "follow", "Follow symbolic links while walking directories"),
+    flag(None, "no-ignore", "Don't honor .gitignore, .ignore and .kkjgrepignore files"),
+    valued(Some('j'), "threads", "NUM", "Search NUM files at once; 0, the default, uses one per CPU"),
+    flag(None, "unordered", "Print each file as soon as it's searched, not in the order found"),
+    flag(None, "mmap", "Memory-map every file instead of only large ones"),
+    flag(None, "no-mmap", "Never memory-map files; always read them"),
+    flag(Some('h'), "help", "Print this help and exit"),
+    flag(Some('V'), "version", "Print the version and exit"),
+];
+
+const USAGE: &str = "Usage: kkjgrep [OPTIONS] QUERY [PATH]...\n       kkjgrep [OPTIONS] -e PATTERN... [PATH]...";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// `--help` was given; carries the text to print.
+    Help(String),
+    /// `--version` was given; carries the text to print.
+    Version(String),
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Help(text) | Error::Version(text) | Error::Invalid(text) => f.write_str(text),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Parsed options in the order they were given, plus the positional
+/// arguments.
+#[derive(Debug, Default)]
+pub struct Args {
+    opts: Vec<(&'static str, Option<String>)>,
+    pub positional: Vec<String>,
+}
+
+impl Args {
+    /// Parses arguments, not including the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Args, Error> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                parsed.positional.extend(args.by_ref());
+            } else if let Some(long) = arg.strip_prefix("--") {
+                let (name, value) = match long.split_once('=') {
+                    Some((name, value)) => (name, Some(value.to_string())),
+                    None => (long, None),
+                };
+                let opt = OPTIONS
+                    .iter()
+                    .find(|opt| opt.long == name)
+                    .ok_or_else(|| invalid(format!("unknown option `--{name}`")))?;
+                let value = match (opt.value, value) {
+                    (Some(_), Some(value)) => Some(value),
+                    (Some(_), None) => Some(next_value(&mut args, &arg)?),
+                    (None, Some(_)) => return Err(invalid(format!("option `--{name}` doesn't take a value"))),
+                    (None, None) => None,
+                };
+                parsed.push(opt, value)?;
+            } else if arg.len() > 1 && arg.starts_with('-') {
+                for (i, c) in arg[1..].char_indices() {
+                    let opt = OPTIONS
+                        .iter()
+                        .find(|opt| opt.short == Some(c))
+                        .ok_or_else(|| invalid(format!("unknown option `-{c}`")))?;
+                    if opt.value.is_none() {
+                        parsed.push(opt, None)?;
+                        continue;
+                    }
+
+                    // the rest of the cluster, if any, is the value
+                    let rest = &arg[1 + i + c.len_utf8()..];
+                    let value = if rest.is_empty() {
+                        next_value(&mut args, &format!("-{c}"))?
+                    } else {
+                        rest.to_string()
+                    };
+                    parsed.push(opt, Some(value))?;
+                    break;
+                }
+            } else {
+                parsed.positional.push(arg);
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    fn push(&mut self, opt: &'static Opt, value: Option<String>) -> Result<(), Error> {
+        match opt.long {
+            "help" => Err(Error::Help(help())),
+            "version" => Err(Error::Version(format!("kkjgrep {}", env!("CARGO_PKG_VERSION")))),
+            long => {
+                self.opts.push((long, value));
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether the option with this long name was given.
+    pub fn flag(&self, long: &str) -> bool {
+        self.opts.iter().any(|(name, _)| *name == long)
+    }
+
+    /// The value of the last occurrence of the option with this long name.
+    pub fn value(&self, long: &str) -> Option<&str> {
+        self.opts
+            .iter()
+            .rev()
+            .find(|(name, _)| *name == long)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    /// Every occurrence of the given options, in order, with its value.
+    pub fn all_of<'a>(&'a self, longs: &'a [&str]) -> impl Iterator<Item = (&'a str, Option<&'a str>)> + 'a {
+        self.opts
+            .iter()
+            .filter(|(name, _)| longs.contains(name))
+            .map(|(name, value)| (*name, value.as_deref()))
+    }
+
+    /// Which of the given options was given last, with its value.
+    pub fn last_of(&self, longs: &[&str]) -> Option<(&str, Option<&str>)> {
+        self.opts
+            .iter()
+            .rev()
+            .find(|(name, _)| longs.contains(name))
+            .map(|(name, value)| (*name, value.as_deref()))
+    }
+
+    /// Parses the value of the option with this long name as a number.
+    pub fn number(&self, long: &str) -> Result<Option<usize>, Error> {
+        self.value(long)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| invalid(format!("option `--{long}` expects a number, got `{value}`")))
+            })
+            .transpose()
+    }
+}
+
+pub(crate) fn invalid(message: String) -> Error {
+    Error::Invalid(message)
+}
+
+fn next_value(args: &mut impl Iterator<Item = String>, name: &str) -> Result<String, Error> {
+    args.next().ok_or_else(|| invalid(format!("option `{name}` needs a value")))
+}
+
+pub fn help() -> String {
+    let usages: Vec<String> = OPTIONS
+        .iter()
+        .map(|opt| {
+            let short = opt.short.map_or("    ".to_string(), |c| format!("-{c}, "));
+            let value = opt.value.map_or(String::new(), |value| format!("={value}"));
+            format!("{short}--{}{value}", opt.long)
+        })
+        .collect();
+    let width = usages.iter().map(String::len).max().unwrap_or(0);
+
+    let mut text = format!("{USAGE}\n\nSearch for QUERY in each PATH, or standard input when PATH is `-` or missing.\n\nOptions:\n");
+    for (usage, opt) in usages.iter().zip(OPTIONS) {
+        text.push_str(&format!("  {usage:width$}  {}\n", opt.help));
+    }
+    text.push_str("\nEnvironment:\n");
+    text.push_str("  IGNORE_CASE=1   Search case-insensitively unless -s is given; IGNORE_CASE=0 disables -i and -S\n");
+    text.push_str("  NO_COLOR        Don't color output unless --color=always is given\n");
+    text.push_str("  KKJGREP_COLORS  Colors to use, e.g. match=1;31:path=35:line=32:separator=36\n");
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, Error> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn short_long_and_combined() {
+        let args = parse(&["-in", "query", "--replace=new", "path", "-A3", "-B", "2", "--context", "1"]).unwrap();
+
+        assert!(args.flag("ignore-case"));
+        assert!(args.flag("line-number"));
+        assert_eq!(Some("new"), args.value("replace"));
+        assert_eq!(Some(3), args.number("after-context").unwrap());
+        assert_eq!(Some(2), args.number("before-context").unwrap());
+        assert_eq!(Some(1), args.number("context").unwrap());
+        assert_eq!(vec!["query", "path"], args.positional);
+    }
+
+    #[test]
+    fn values_are_never_positional() {
+        let args = parse(&["-r", "path-like", "query", "-nr", "-x", "-", "--", "-s", "--help"]).unwrap();
+
+        assert_eq!(Some("-x"), args.value("replace"));
+        assert!(!args.flag("case-sensitive"));
+        assert_eq!(vec!["query", "-", "-s", "--help"], args.positional);
+    }
+
+    #[test]
+    fn last_of() {
+        let args = parse(&["-F", "-E", "--fixed-strings"]).unwrap();
+
+        assert_eq!(Some(("fixed-strings", None)), args.last_of(&["fixed-strings", "regexp"]));
+        assert_eq!(None, args.last_of(&["text"]));
+    }
+
+    #[test]
+    fn all_of() {
+        let args = parse(&["-e", "a", "-i", "--file=list", "-eb"]).unwrap();
+        let all: Vec<_> = args.all_of(&["pattern", "file"]).collect();
+
+        assert_eq!(vec![("pattern", Some("a")), ("file", Some("list")), ("pattern", Some("b"))], all);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Err(invalid("unknown option `--nope`".to_string())), parse(&["--nope"]).map(|_| ()));
+        assert_eq!(Err(invalid("unknown option `-z`".to_string())), parse(&["-iz"]).map(|_| ()));
+        assert_eq!(Err(invalid("option `-A` needs a value".to_string())), parse(&["-A"]).map(|_| ()));
+        assert!(matches!(parse(&["--follow=yes"]), Err(Error::Invalid(_))));
+        assert!(parse(&["-A", "x"]).unwrap().number("after-context").is_err());
+        assert!(matches!(parse(&["-ih"]), Err(Error::Help(text)) if text.contains("--ignore-case")));
+        assert!(matches!(parse(&["--version"]), Err(Error::Version(_))));
+    }
+}
@@ -0,0 +1,166 @@
+//! Unicode case folding for case-insensitive matching.
+//!
+//! Folding is derived from the standard library's case mappings: a char
+//! folds to the lowercase of its uppercase, taken twice so that every case
+//! variant ends up in the same place. `ß`, `ẞ` and `SS` all fold to `ss`,
+//! `ſ` to `s` and final `ς` to `σ`. As in Unicode's `CaseFolding.txt`, the
+//! Turkish dotless `ı` folds to itself rather than to `i`, and `İ` folds to
+//! `i` followed by a combining dot.
+
+use crate::regex::{self, Bounds};
+
+// no character folds to more than three
+const MAX_FOLDED: usize = 3;
+
+/// The full case folding of one char, as an iterator that doesn't allocate.
+#[derive(Debug, Clone)]
+pub struct Fold {
+    chars: [char; MAX_FOLDED],
+    len: u8,
+    next: u8,
+}
+
+impl Iterator for Fold {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        if self.next == self.len {
+            return None;
+        }
+        self.next += 1;
+        Some(self.chars[self.next as usize - 1])
+    }
+}
+
+impl Fold {
+    /// The chars that haven't been iterated over yet.
+    pub fn as_slice(&self) -> &[char] {
+        &self.chars[self.next as usize..self.len as usize]
+    }
+}
+
+pub fn fold(c: char) -> Fold {
+    let mut folded = Fold { chars: [c; MAX_FOLDED], len: 1, next: 0 };
+    if c.is_ascii() {
+        folded.chars[0] = c.to_ascii_lowercase();
+        return folded;
+    }
+    if c == 'ı' {
+        return folded;
+    }
+
+    folded.len = 0;
+    let chars = c
+        .to_uppercase()
+        .flat_map(char::to_lowercase)
+        .flat_map(char::to_uppercase)
+        .flat_map(char::to_lowercase);
+    for c in chars.take(MAX_FOLDED) {
+        folded.chars[folded.len as usize] = c;
+        folded.len += 1;
+    }
+    folded
+}
+
+/// The simple case folding of one char: like [`fold`], but chars that
+/// fold to several chars are kept as one, so `ß` and `ẞ` both become `ß`.
+pub fn simple(c: char) -> char {
+    if c == 'ı' {
+        return c;
+    }
+    let upper = single(c.to_uppercase()).unwrap_or(c);
+    single(upper.to_lowercase()).unwrap_or(upper)
+}
+
+fn single(mut chars: impl Iterator<Item = char>) -> Option<char> {
+    let c = chars.next()?;
+    chars.next().is_none().then_some(c)
+}
+
+/// A fixed string to search for case-insensitively.
+#[derive(Debug, Clone)]
+pub struct Needle {
+    folded: Vec<char>,
+    bounds: Bounds,
+}
+
+impl Needle {
+    pub fn new(needle: &str, bounds: Bounds) -> Needle {
+        Needle { folded: needle.chars().flat_map(fold).collect(), bounds }
+    }
+
+    /// Byte range of the first occurrence at or after `start` that lines up
+    /// with the bounds. Matches start and end on character boundaries, and
+    /// the haystack is folded on the fly, so nothing is allocated.
+    pub fn find_at(&self, haystack: &[u8], start: usize) -> Option<(usize, usize)> {
+        let mut pos = start;
+        while pos <= haystack.len() {
+            if let Some(end) = self.match_at(haystack, pos) {
+                if self.bounds.holds(haystack, pos, end) {
+                    return Some((pos, end));
+                }
+            }
+            pos += regex::decode(haystack, pos).map_or(1, |(_, len)| len);
+        }
+        None
+    }
+
+    // end of the match starting at pos, if there is one
+    fn match_at(&self, haystack: &[u8], mut pos: usize) -> Option<usize> {
+        let mut i = 0;
+        while i < self.folded.len() {
+            let (c, len) = regex::decode(haystack, pos)?;
+            for folded in fold(c?) {
+                if self.folded.get(i) != Some(&folded) {
+                    return None;
+                }
+                i += 1;
+            }
+            pos += len;
+        }
+        Some(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folded(s: &str) -> String {
+        s.chars().flat_map(fold).collect()
+    }
+
+    fn find(needle: &str, haystack: &str) -> Option<(usize, usize)> {
+        Needle::new(needle, Bounds::Any).find_at(haystack.as_bytes(), 0)
+    }
+
+    #[test]
+    fn full_folding() {
+        assert_eq!("strasse", folded("STRAßE"));
+        assert_eq!(folded("ẞ"), folded("ss"));
+        assert_eq!(folded("ΌΣΟΣ"), folded("όσος"));
+        assert_eq!("i\u{307}ıi", folded("İıI"));
+        assert_eq!('ß', simple('ẞ'));
+        assert_eq!('ı', simple('ı'));
+    }
+
+    #[test]
+    fn find_folded() {
+        assert_eq!(Some((4, 11)), find("straße", "Die STRASSE"));
+        assert_eq!(Some((4, 11)), find("STRASSE", "Die Straße"));
+        assert_eq!(None, find("stras", "Die Straße"));
+        assert_eq!(Some((0, 10)), find("ΣΟΦΟΣ", "σοφος"));
+        assert_eq!(None, find("istanbul", "ıstanbul"));
+        assert_eq!(Some((0, 9)), find("i̇stanbul", "İstanbul"));
+        assert_eq!(Some((1, 1)), Needle::new("", Bounds::Any).find_at(b"ab", 1));
+    }
+
+    #[test]
+    fn invalid_utf8_and_bounds() {
+        let needle = Needle::new("é", Bounds::Any);
+        assert_eq!(Some((2, 4)), needle.find_at(b"\xffa\xc3\xa9", 0));
+
+        let needle = Needle::new("ID", Bounds::Word);
+        assert_eq!(Some((11, 13)), needle.find_at(b"valid; Ids id", 0));
+    }
+}
@@ -0,0 +1,124 @@
+//! ANSI colors for matches, file names and line numbers.
+//!
+//! The palette can be customized with `KKJGREP_COLORS`, a colon-separated
+//! list of `NAME=SGR` pairs such as `match=1;31:path=35:line=32:separator=36`.
+//! An empty SGR turns coloring off for that element.
+
+use std::io::{self, Write};
+
+pub const COLORS_ENV: &str = "KKJGREP_COLORS";
+
+/// When to color output, as given with `--color`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorChoice {
+    /// Color only when writing to a terminal and `NO_COLOR` isn't set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn parse(value: &str) -> Option<ColorChoice> {
+        match value {
+            "auto" => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            "never" => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+}
+
+/// SGR parameters for each colored element of the output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Colors {
+    pub matched: String,
+    pub path: String,
+    pub line_number: String,
+    pub separator: String,
+}
+
+impl Default for Colors {
+    fn default() -> Colors {
+        Colors {
+            matched: "1;31".to_string(),
+            path: "35".to_string(),
+            line_number: "32".to_string(),
+            separator: "36".to_string(),
+        }
+    }
+}
+
+impl Colors {
+    /// A palette that writes no escape sequences at all.
+    pub fn plain() -> Colors {
+        Colors {
+            matched: String::new(),
+            path: String::new(),
+            line_number: String::new(),
+            separator: String::new(),
+        }
+    }
+
+    /// The default palette with the overrides in `spec` applied.
+    pub fn parse(spec: &str) -> Result<Colors, String> {
+        let mut colors = Colors::default();
+
+        for entry in spec.split(':').filter(|entry| !entry.is_empty()) {
+            let (name, sgr) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected NAME=SGR, got `{entry}`"))?;
+            if !sgr.bytes().all(|b| b.is_ascii_digit() || b == b';') {
+                return Err(format!("`{sgr}` isn't a list of SGR parameters"));
+            }
+
+            let slot = match name {
+                "match" => &mut colors.matched,
+                "path" => &mut colors.path,
+                "line" => &mut colors.line_number,
+                "separator" => &mut colors.separator,
+                _ => return Err(format!("unknown color `{name}`")),
+            };
+            *slot = sgr.to_string();
+        }
+
+        Ok(colors)
+    }
+}
+
+/// Writes `text` wrapped in the escape sequences for `sgr`, or as is when
+/// `sgr` is empty.
+pub fn paint(out: &mut impl Write, sgr: &str, text: &[u8]) -> io::Result<()> {
+    if sgr.is_empty() {
+        return out.write_all(text);
+    }
+    write!(out, "\x1b[{sgr}m")?;
+    out.write_all(text)?;
+    out.write_all(b"\x1b[0m")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_palette() {
+        let colors = Colors::parse("match=4;33::line=").unwrap();
+
+        assert_eq!("4;33", colors.matched);
+        assert_eq!("35", colors.path);
+        assert_eq!("", colors.line_number);
+        assert!(Colors::parse("match=red").is_err());
+        assert!(Colors::parse("file=35").is_err());
+        assert!(Colors::parse("match").is_err());
+    }
+
+    #[test]
+    fn paint_wraps_in_escapes() {
+        let mut out = Vec::new();
+        paint(&mut out, "1;31", b"rust").unwrap();
+        paint(&mut out, "", b" and go").unwrap();
+
+        assert_eq!(b"\x1b[1;31mrust\x1b[0m and go".to_vec(), out);
+    }
+}
@@ -0,0 +1,215 @@
+//! Unified diffs of what `--replace` would change, in the format `patch -p1`
+//! applies.
+//!
+//! Replacements never add or remove lines, so the diff is computed line by
+//! line while streaming the file; only the hunk being built is kept in
+//! memory.
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+
+/// Lines of unchanged context around each change.
+pub const CONTEXT: usize = 3;
+
+enum Entry {
+    Context(Vec<u8>),
+    Changed(Vec<u8>, Vec<u8>),
+}
+
+struct Hunk {
+    // 1-based number of the first line in the hunk
+    start: usize,
+    entries: Vec<Entry>,
+    // unchanged lines since the last change
+    trailing: usize,
+}
+
+/// Writes the diff between `reader` and `reader` with `replace` applied to
+/// every line, labelled with `path`. Returns the number of changed lines;
+/// nothing is written when there are none.
+pub fn write_diff<R, F, W>(mut reader: R, path: &str, replace: F, out: &mut W) -> io::Result<usize>
+where
+    R: BufRead,
+    F: for<'a> Fn(&'a [u8]) -> Cow<'a, [u8]>,
+    W: Write,
+{
+    let path = path.strip_prefix("./").unwrap_or(path);
+    let mut recent: VecDeque<Vec<u8>> = VecDeque::with_capacity(CONTEXT);
+    let mut hunk: Option<Hunk> = None;
+    let mut changed = 0;
+    let mut line_number = 0;
+    let mut buf = Vec::new();
+
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+        line_number += 1;
+
+        let (line, terminator) = split_terminator(&buf);
+        let replaced = replace(line);
+
+        if replaced.as_ref() != line {
+            if changed == 0 {
+                writeln!(out, "--- a/{path}\n+++ b/{path}")?;
+            }
+            changed += 1;
+
+            let hunk = hunk.get_or_insert_with(|| Hunk {
+                start: line_number - recent.len(),
+                entries: recent.drain(..).map(Entry::Context).collect(),
+                trailing: 0,
+            });
+            let new = [replaced.as_ref(), terminator].concat();
+            hunk.entries.push(Entry::Changed(buf.clone(), new));
+            hunk.trailing = 0;
+        } else if let Some(current) = hunk.as_mut() {
+            current.entries.push(Entry::Context(buf.clone()));
+            current.trailing += 1;
+
+            if current.trailing > 2 * CONTEXT {
+                // too far from the last change to share a hunk with the next one
+                let mut finished = hunk.take().unwrap();
+                let keep = finished.entries.len() - (finished.trailing - CONTEXT);
+                recent.extend(finished.entries.drain(keep..).skip(1).filter_map(|entry| match entry {
+                    Entry::Context(line) => Some(line),
+                    Entry::Changed(..) => None,
+                }));
+                write_hunk(out, &finished)?;
+            }
+        } else {
+            if recent.len() == CONTEXT {
+                recent.pop_front();
+            }
+            recent.push_back(buf.clone());
+        }
+    }
+
+    if let Some(mut finished) = hunk {
+        let keep = finished.entries.len() - finished.trailing.saturating_sub(CONTEXT);
+        finished.entries.truncate(keep);
+        write_hunk(out, &finished)?;
+    }
+
+    Ok(changed)
+}
+
+fn write_hunk<W: Write>(out: &mut W, hunk: &Hunk) -> io::Result<()> {
+    let len = hunk.entries.len();
+    writeln!(out, "@@ -{start},{len} +{start},{len} @@", start = hunk.start)?;
+
+    let mut i = 0;
+    while i < len {
+        match &hunk.entries[i] {
+            Entry::Context(line) => {
+                write_line(out, b' ', line)?;
+                i += 1;
+            }
+            Entry::Changed(..) => {
+                // a run of changed lines is shown as all removals, then all additions
+                let run: Vec<(&Vec<u8>, &Vec<u8>)> = hunk.entries[i..]
+                    .iter()
+                    .map_while(|entry| match entry {
+                        Entry::Changed(old, new) => Some((old, new)),
+                        Entry::Context(_) => None,
+                    })
+                    .collect();
+                for (old, _) in &run {
+                    write_line(out, b'-', old)?;
+                }
+                for (_, new) in &run {
+                    write_line(out, b'+', new)?;
+                }
+                i += run.len();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn write_line<W: Write>(out: &mut W, marker: u8, line: &[u8]) -> io::Result<()> {
+    out.write_all(&[marker])?;
+    out.write_all(line)?;
+    if !line.ends_with(b"\n") {
+        out.write_all(b"\n\\ No newline at end of file\n")?;
+    }
+    Ok(())
+}
+
+fn split_terminator(buf: &[u8]) -> (&[u8], &[u8]) {
+    let len = if buf.ends_with(b"\r\n") {
+        buf.len() - 2
+    } else if buf.ends_with(b"\n") {
+        buf.len() - 1
+    } else {
+        buf.len()
+    };
+    buf.split_at(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replace(line: &[u8]) -> Cow<'_, [u8]> {
+        match line {
+            b"x" => Cow::Owned(b"y".to_vec()),
+            _ => Cow::Borrowed(line),
+        }
+    }
+
+    fn diff(contents: &str) -> String {
+        let mut out = Vec::new();
+        write_diff(contents.as_bytes(), "./dir/file.txt", replace, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn hunks_merge_and_split() {
+        let contents = "1\nx\n3\n4\n5\n6\n7\n8\nx\nx\n11\n12\n13\n14\n15\n16\n17\n18\nx\n20\n";
+
+        assert_eq!(
+            "\
+--- a/dir/file.txt
++++ b/dir/file.txt
+@@ -1,13 +1,13 @@
+ 1
+-x
++y
+ 3
+ 4
+ 5
+ 6
+ 7
+ 8
+-x
+-x
++y
++y
+ 11
+ 12
+ 13
+@@ -16,5 +16,5 @@
+ 16
+ 17
+ 18
+-x
++y
+ 20
+",
+            diff(contents)
+        );
+    }
+
+    #[test]
+    fn missing_newline_at_end() {
+        assert_eq!(
+            "--- a/dir/file.txt\n+++ b/dir/file.txt\n@@ -1,2 +1,2 @@\n a\n-x\n\\ No newline at end of file\n+y\n\\ No newline at end of file\n",
+            diff("a\nx")
+        );
+        assert_eq!("", diff("a\nb\n"));
+    }
+}
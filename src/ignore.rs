@@ -0,0 +1,350 @@
+//! Gitignore-style rules used to prune directory walks.
+//!
+//! Rules are read from `.gitignore`, `.ignore` and `.kkjgrepignore` in every
+//! directory (later files win) and from `.git/info/exclude` at the root of
+//! the repository. Patterns follow gitignore semantics: `!` negates, a
+//! trailing `/` only matches directories, a `/` anywhere else anchors the
+//! pattern to the directory of the file it came from, and `*`, `?`,
+//! `[...]` and `**` are globs.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const IGNORE_FILES: [&str; 3] = [".gitignore", ".ignore", ".kkjgrepignore"];
+
+/// An ordered list of rules. Paths are given relative to the repository
+/// root with `/` separators.
+#[derive(Debug, Default, Clone)]
+pub struct Gitignore {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    // directory the rule was read from, relative to the repository root
+    base: String,
+    glob: Vec<Token>,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    Any,
+    Star,
+    // `**` matching any sequence, including slashes
+    DoubleStar,
+    // `**/` matching zero or more leading directories
+    DirStar,
+    Class { ranges: Vec<(char, char)>, negated: bool },
+}
+
+impl Gitignore {
+    pub fn parse(contents: &str, base: &str) -> Gitignore {
+        let rules = contents
+            .lines()
+            .filter_map(|line| Rule::parse(line, base))
+            .collect();
+
+        Gitignore { rules }
+    }
+
+    /// Reads the ignore files found directly in `dir`.
+    pub fn from_dir(dir: &Path, base: &str) -> Gitignore {
+        let mut gitignore = Gitignore::default();
+        for name in IGNORE_FILES {
+            gitignore.extend_from_file(&dir.join(name), base);
+        }
+        gitignore
+    }
+
+    /// Collects the rules that apply to `root` from the repository it lives
+    /// in: `.git/info/exclude` and the ignore files of every directory
+    /// between the repository root and `root` itself. Also returns the
+    /// path of `root` relative to the repository root.
+    pub fn for_root(root: &Path) -> (String, Gitignore) {
+        let mut gitignore = Gitignore::default();
+        let Ok(root) = fs::canonicalize(root) else {
+            return (String::new(), gitignore);
+        };
+        let Some(repo) = root.ancestors().find(|dir| dir.join(".git").exists()) else {
+            return (String::new(), gitignore);
+        };
+
+        gitignore.extend_from_file(&repo.join(".git/info/exclude"), "");
+
+        let prefix = relative(root.strip_prefix(repo).unwrap_or(Path::new("")));
+        let mut dir = PathBuf::from(repo);
+        let mut base = String::new();
+        for component in Path::new(&prefix).components() {
+            gitignore.rules.extend(Gitignore::from_dir(&dir, &base).rules);
+            dir.push(component);
+            base = join(&base, &component.as_os_str().to_string_lossy());
+        }
+
+        (prefix, gitignore)
+    }
+
+    fn extend_from_file(&mut self, path: &Path, base: &str) {
+        if let Ok(contents) = fs::read_to_string(path) {
+            self.rules.extend(Gitignore::parse(&contents, base).rules);
+        }
+    }
+
+    /// `Some(true)` if the last rule matching `path` ignores it,
+    /// `Some(false)` if it re-includes it and `None` if nothing matched.
+    pub fn matched(&self, path: &str, is_dir: bool) -> Option<bool> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(path, is_dir))
+            .map(|rule| !rule.negated)
+    }
+}
+
+/// Joins a relative directory and a name with `/`.
+pub fn join(base: &str, name: &str) -> String {
+    if base.is_empty() || name.is_empty() {
+        format!("{base}{name}")
+    } else {
+        format!("{base}/{name}")
+    }
+}
+
+/// Turns a relative path into the `/`-separated form rules match against.
+pub fn relative(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+impl Rule {
+    fn parse(line: &str, base: &str) -> Option<Rule> {
+        let line = trim_trailing_spaces(line);
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) if !rest.ends_with('\\') => (true, rest),
+            _ => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+
+        Some(Rule {
+            base: base.to_string(),
+            glob: tokenize(line),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        let path = if self.base.is_empty() {
+            path
+        } else {
+            match path.strip_prefix(&self.base).and_then(|rest| rest.strip_prefix('/')) {
+                Some(rest) => rest,
+                None => return false,
+            }
+        };
+
+        if self.anchored {
+            glob_match(&self.glob, path)
+        } else {
+            glob_match(&self.glob, path.rsplit('/').next().unwrap_or(path))
+        }
+    }
+}
+
+// trailing spaces are dropped unless they are escaped with a backslash
+fn trim_trailing_spaces(line: &str) -> &str {
+    let mut end = line.len();
+    while line[..end].ends_with(' ') {
+        if line[..end - 1].ends_with('\\') {
+            break;
+        }
+        end -= 1;
+    }
+    &line[..end]
+}
+
+fn tokenize(glob: &str) -> Vec<Token> {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                tokens.push(Token::Char(chars[i + 1]));
+                i += 2;
+                continue;
+            }
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                let at_start = i == 0 || chars[i - 1] == '/';
+                match chars.get(i + 2) {
+                    Some('/') if at_start => {
+                        tokens.push(Token::DirStar);
+                        i += 3;
+                        continue;
+                    }
+                    None if at_start => tokens.push(Token::DoubleStar),
+                    _ => tokens.push(Token::Star),
+                }
+                i += 2;
+                continue;
+            }
+            '*' => tokens.push(Token::Star),
+            '?' => tokens.push(Token::Any),
+            '[' => {
+                if let Some((class, len)) = parse_class(&chars[i..]) {
+                    tokens.push(class);
+                    i += len;
+                    continue;
+                }
+                tokens.push(Token::Char('['));
+            }
+            c => tokens.push(Token::Char(c)),
+        }
+        i += 1;
+    }
+
+    tokens
+}
+
+// returns the class and how many chars it spans, or None if unterminated
+fn parse_class(chars: &[char]) -> Option<(Token, usize)> {
+    let mut i = 1;
+    let negated = matches!(chars.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut ranges = Vec::new();
+    let mut first = true;
+    loop {
+        let mut c = *chars.get(i)?;
+        if c == ']' && !first {
+            return Some((Token::Class { ranges, negated }, i + 1));
+        }
+        first = false;
+        if c == '\\' {
+            i += 1;
+            c = *chars.get(i)?;
+        }
+        i += 1;
+
+        if chars.get(i) == Some(&'-') && chars.get(i + 1).is_some_and(|&c| c != ']') {
+            ranges.push((c, chars[i + 1]));
+            i += 2;
+        } else {
+            ranges.push((c, c));
+        }
+    }
+}
+
+fn glob_match(tokens: &[Token], text: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    let n = text.len();
+    // dp[i][j]: tokens[i..] matches text[j..]
+    let mut dp = vec![vec![false; n + 1]; tokens.len() + 1];
+    dp[tokens.len()][n] = true;
+
+    for i in (0..tokens.len()).rev() {
+        for j in (0..=n).rev() {
+            let c = text.get(j).copied();
+            let single = |ok: bool| ok && dp[i + 1][j + 1];
+            dp[i][j] = match &tokens[i] {
+                Token::Char(x) => single(c == Some(*x)),
+                Token::Any => single(c.is_some_and(|c| c != '/')),
+                Token::Class { ranges, negated } => single(c.is_some_and(|c| {
+                    c != '/' && ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+                })),
+                Token::Star => dp[i + 1][j] || c.is_some_and(|c| c != '/') && dp[i][j + 1],
+                Token::DoubleStar => dp[i + 1][j] || c.is_some() && dp[i][j + 1],
+                Token::DirStar => {
+                    dp[i + 1][j] || (j + 1..=n).any(|k| text[k - 1] == '/' && dp[i + 1][k])
+                }
+            };
+        }
+    }
+
+    dp[0][0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(rules: &str, path: &str, is_dir: bool) -> Option<bool> {
+        Gitignore::parse(rules, "").matched(path, is_dir)
+    }
+
+    #[test]
+    fn basenames_match_at_any_depth() {
+        assert_eq!(Some(true), ignored("*.log", "a/b/debug.log", false));
+        assert_eq!(Some(true), ignored("target", "crates/x/target", true));
+        assert_eq!(None, ignored("*.log", "a/b/debug.txt", false));
+        assert_eq!(Some(true), ignored("file[0-9].txt", "file7.txt", false));
+        assert_eq!(None, ignored("file[!0-9].txt", "file7.txt", false));
+    }
+
+    #[test]
+    fn anchored_and_directory_rules() {
+        assert_eq!(Some(true), ignored("/build", "build", true));
+        assert_eq!(None, ignored("/build", "src/build", true));
+        assert_eq!(Some(true), ignored("doc/*.html", "doc/index.html", false));
+        assert_eq!(None, ignored("doc/*.html", "doc/api/index.html", false));
+        assert_eq!(Some(true), ignored("node_modules/", "web/node_modules", true));
+        assert_eq!(None, ignored("node_modules/", "web/node_modules", false));
+    }
+
+    #[test]
+    fn double_star() {
+        assert_eq!(Some(true), ignored("**/gen", "a/b/gen", true));
+        assert_eq!(Some(true), ignored("**/gen", "gen", false));
+        assert_eq!(Some(true), ignored("a/**/z", "a/z", false));
+        assert_eq!(Some(true), ignored("a/**/z", "a/b/c/z", false));
+        assert_eq!(Some(true), ignored("out/**", "out/x/y.o", false));
+        assert_eq!(None, ignored("out/**", "out", true));
+    }
+
+    #[test]
+    fn negation_and_escapes() {
+        let rules = "# generated\n*.rs\n!keep.rs\n\\#hash\n\\!bang\ntrailing\\ \n";
+
+        assert_eq!(Some(true), ignored(rules, "src/lib.rs", false));
+        assert_eq!(Some(false), ignored(rules, "src/keep.rs", false));
+        assert_eq!(Some(true), ignored(rules, "#hash", false));
+        assert_eq!(Some(true), ignored(rules, "!bang", false));
+        assert_eq!(Some(true), ignored(rules, "trailing ", false));
+        assert_eq!(None, ignored(rules, "# generated", false));
+    }
+
+    #[test]
+    fn rules_are_relative_to_their_file() {
+        let gitignore = Gitignore::parse("/out\n*.tmp", "sub/dir");
+
+        assert_eq!(Some(true), gitignore.matched("sub/dir/out", true));
+        assert_eq!(None, gitignore.matched("out", true));
+        assert_eq!(Some(true), gitignore.matched("sub/dir/x/a.tmp", false));
+        assert_eq!(None, gitignore.matched("other/a.tmp", false));
+    }
+}
@@ -0,0 +1,189 @@
+//! JSON Lines output for `--json`: one object per line, each with a `type`.
+//!
+//! The schema is stable; new fields may be added but existing ones won't
+//! change meaning.
+//!
+//! ```text
+//! {"type":"begin","path":DATA}
+//! {"type":"match","path":DATA,"line_number":N,"absolute_offset":N,"line":DATA,"submatches":[SUBMATCH...]}
+//! {"type":"context","path":DATA,"line_number":N,"absolute_offset":N,"line":DATA,"submatches":[]}
+//! {"type":"end","path":DATA,"binary":BOOL,"stats":STATS}
+//! {"type":"summary","stats":STATS}
+//! ```
+//!
+//! - `DATA` is `{"text":"..."}` when the bytes are valid UTF-8 and
+//!   `{"bytes":"..."}` with the bytes in base64 otherwise.
+//! - `line` is the line without its terminator, `line_number` is 1-based
+//!   and `absolute_offset` is the byte offset of the line in the input.
+//! - `SUBMATCH` is `{"match":DATA,"start":N,"end":N}`, with `start` and
+//!   `end` the byte range of the match within `line`. Lines selected with
+//!   `-v` have no submatches.
+//! - `STATS` is `{"searches":N,"searches_with_match":N,"matched_lines":N,"matches":N}`.
+//!   In an `end` event it covers that input only.
+//! - `begin` and `end` are only written for inputs with a match. `binary`
+//!   is true when the input is binary and its matches weren't printed.
+
+use std::io::{self, Write};
+
+use crate::Match;
+
+/// Counts reported in `end` and `summary` events.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub searches: usize,
+    pub searches_with_match: usize,
+    pub matched_lines: usize,
+    pub matches: usize,
+}
+
+impl Stats {
+    pub fn add(&mut self, other: &Stats) {
+        self.searches += other.searches;
+        self.searches_with_match += other.searches_with_match;
+        self.matched_lines += other.matched_lines;
+        self.matches += other.matches;
+    }
+}
+
+pub fn write_begin(out: &mut impl Write, path: &str) -> io::Result<()> {
+    out.write_all(br#"{"type":"begin","path":"#)?;
+    write_data(out, path.as_bytes())?;
+    out.write_all(b"}\n")
+}
+
+/// Writes a `match` event, or a `context` event when `matched` is false.
+pub fn write_line(
+    out: &mut impl Write,
+    path: &str,
+    m: &Match,
+    matched: bool,
+    submatches: &[(usize, usize)],
+) -> io::Result<()> {
+    let kind = if matched { "match" } else { "context" };
+    write!(out, r#"{{"type":"{kind}","path":"#)?;
+    write_data(out, path.as_bytes())?;
+    write!(out, r#","line_number":{},"absolute_offset":{},"line":"#, m.line_number, m.byte_offset)?;
+    write_data(out, m.line)?;
+
+    out.write_all(br#","submatches":["#)?;
+    for (i, &(start, end)) in submatches.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        out.write_all(br#"{"match":"#)?;
+        write_data(out, &m.line[start..end])?;
+        write!(out, r#","start":{start},"end":{end}}}"#)?;
+    }
+    out.write_all(b"]}\n")
+}
+
+pub fn write_end(out: &mut impl Write, path: &str, binary: bool, stats: &Stats) -> io::Result<()> {
+    out.write_all(br#"{"type":"end","path":"#)?;
+    write_data(out, path.as_bytes())?;
+    write!(out, r#","binary":{binary},"stats":"#)?;
+    write_stats(out, stats)?;
+    out.write_all(b"}\n")
+}
+
+pub fn write_summary(out: &mut impl Write, stats: &Stats) -> io::Result<()> {
+    out.write_all(br#"{"type":"summary","stats":"#)?;
+    write_stats(out, stats)?;
+    out.write_all(b"}\n")
+}
+
+fn write_stats(out: &mut impl Write, stats: &Stats) -> io::Result<()> {
+    write!(
+        out,
+        r#"{{"searches":{},"searches_with_match":{},"matched_lines":{},"matches":{}}}"#,
+        stats.searches, stats.searches_with_match, stats.matched_lines, stats.matches
+    )
+}
+
+fn write_data(out: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => {
+            out.write_all(br#"{"text":"#)?;
+            write_string(out, text)?;
+        }
+        Err(_) => {
+            out.write_all(br#"{"bytes":""#)?;
+            out.write_all(base64(bytes).as_bytes())?;
+            out.write_all(b"\"")?;
+        }
+    }
+    out.write_all(b"}")
+}
+
+fn write_string(out: &mut impl Write, text: &str) -> io::Result<()> {
+    out.write_all(b"\"")?;
+    // everything that needs escaping is ASCII, so bytes can be scanned directly
+    let bytes = text.as_bytes();
+    let mut last = 0;
+    for (i, &b) in bytes.iter().enumerate() {
+        let escaped = match b {
+            b'"' => "\\\"".to_string(),
+            b'\\' => "\\\\".to_string(),
+            b'\n' => "\\n".to_string(),
+            b'\r' => "\\r".to_string(),
+            b'\t' => "\\t".to_string(),
+            b if b < b' ' => format!("\\u{b:04x}"),
+            _ => continue,
+        };
+        out.write_all(&bytes[last..i])?;
+        out.write_all(escaped.as_bytes())?;
+        last = i + 1;
+    }
+    out.write_all(&bytes[last..])?;
+    out.write_all(b"\"")
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(f: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> String {
+        let mut out = Vec::new();
+        f(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn match_event() {
+        let m = Match { line_number: 2, byte_offset: 7, line: b"say \"hi\"\tnow", span: None };
+
+        assert_eq!(
+            "{\"type\":\"match\",\"path\":{\"text\":\"a.txt\"},\"line_number\":2,\"absolute_offset\":7,\
+             \"line\":{\"text\":\"say \\\"hi\\\"\\tnow\"},\
+             \"submatches\":[{\"match\":{\"text\":\"\\\"hi\\\"\"},\"start\":4,\"end\":8}]}\n",
+            render(|out| write_line(out, "a.txt", &m, true, &[(4, 8)]))
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_as_base64() {
+        let m = Match { line_number: 1, byte_offset: 0, line: b"\xffab\x01", span: None };
+
+        assert!(render(|out| write_line(out, "a", &m, false, &[])).contains(r#""line":{"bytes":"/2FiAQ=="}"#));
+        assert_eq!("Zm9vYg==", base64(b"foob"));
+        assert_eq!("Zm9vYmFy", base64(b"foobar"));
+        assert_eq!("\"\\u0001\"", render(|out| write_string(out, "\u{1}")));
+    }
+}
@@ -1,131 +1,959 @@
 use std::error::Error;
-use std::{env, fs};
+use std::borrow::Cow;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+pub mod aho_corasick;
+pub mod args;
+pub mod casefold;
+pub mod color;
+pub mod diff;
+pub mod ignore;
+pub mod json;
+pub mod matcher;
+pub mod memchr;
+pub mod mmap;
+pub mod parallel;
+pub mod regex;
+pub mod rewrite;
+pub mod searcher;
+pub mod walk;
+
+use args::Args;
+use color::{ColorChoice, Colors};
+use matcher::{with_case_of, Matcher};
+use mmap::{Mmap, MmapChoice};
+use regex::{Bounds, Regex};
+use searcher::{BinaryFiles, Input, Searcher};
 
 #[derive(Debug)]
 pub struct Config {
-    pub query: String,
-    pub file_path: String,
+    /// What to search for: the query argument, or every `-e` pattern and
+    /// line of a `-f` file. A line matches if any of them matches.
+    pub patterns: Vec<String>,
+    /// Files and directories to search, in the order given. `-` stands for
+    /// standard input, which is also searched when no path is given.
+    pub paths: Vec<String>,
     pub ignore_case: bool,
     pub case_sensitive: bool,
+    /// Ignore case only if no pattern has an uppercase letter.
+    pub smart_case: bool,
     pub line_number: bool,
-    pub replace: String,
+    /// Prefix lines with the 1-based column of the first match.
+    pub column: bool,
+    /// Prefix lines with the byte offset of the first match in the input.
+    pub byte_offset: bool,
+    /// Select the lines that don't match instead of the ones that do.
+    pub invert_match: bool,
+    /// Print every match on its own line instead of the lines they're on.
+    pub only_matching: bool,
+    /// Text to print in place of each match; an empty one deletes them.
+    pub replace: Option<String>,
+    /// Adapt each replacement to the case of the text it replaces.
+    pub preserve_case: bool,
+    /// Write replacements back to the files instead of printing lines.
+    pub in_place: bool,
+    pub backup_suffix: Option<String>,
+    /// Print a unified diff of the replacements instead of printing lines.
+    pub diff: bool,
+    pub fixed_strings: bool,
+    /// Whether matches have to be whole words (`-w`) or whole lines (`-x`).
+    pub bounds: Bounds,
+    /// The compiled query, honoring the case and fixed-string options.
+    pub matcher: Matcher,
+    pub follow_links: bool,
+    pub no_ignore: bool,
+    /// Lines of context to print before and after each match.
+    pub before_context: usize,
+    pub after_context: usize,
+    pub binary_files: BinaryFiles,
+    /// Colors for printed lines; plain when coloring is off.
+    pub colors: Colors,
+    /// Print results as JSON Lines instead of text.
+    pub json: bool,
+    pub report: Report,
+    /// Number of files searched at once, at least one.
+    pub threads: usize,
+    /// Print each file as soon as it has been searched instead of in the
+    /// order the files were found.
+    pub unordered: bool,
+    /// When to memory-map files instead of reading them.
+    pub mmap: MmapChoice,
+}
+
+/// What to print for each searched file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Report {
+    /// The matched lines themselves.
+    #[default]
+    Lines,
+    /// The number of matched lines.
+    Count,
+    /// The number of matches, counting every match on a line. With `-v`,
+    /// the number of selected lines.
+    CountMatches,
+    /// The file name, if the file has a match.
+    FilesWithMatches,
+    /// The file name, if the file has no match.
+    FilesWithoutMatch,
 }
 
 impl Config {
-    pub fn build(
+    pub fn build(args: impl Iterator<Item = String>) -> Result<Config, args::Error> {
+        Self::build_with_env(args, |name| env::var_os(name))
+    }
+
+    /// Like [`Config::build`], but reads environment variables like
+    /// `IGNORE_CASE` and `NO_COLOR` with `env` instead of from the process.
+    pub fn build_with_env(
         mut args: impl Iterator<Item = String>,
-    ) -> Result<Config, &'static str> {
+        env: impl Fn(&str) -> Option<OsString>,
+    ) -> Result<Config, args::Error> {
         args.next();
 
-        let query = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a query string"),
-        };
+        let args = Args::parse(args)?;
+        let mut positional = args.positional.iter().cloned();
 
-        let file_path = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a file path"),
+        let patterns = match Self::find_patterns(&args)? {
+            Some(patterns) => patterns,
+            None => match positional.next() {
+                Some(arg) => vec![arg],
+                None => return Err(args::invalid("Didn't get a query string".to_string())),
+            },
         };
 
-        let args: Vec<String> = args.collect();
+        let mut paths: Vec<String> = positional.collect();
+        if paths.is_empty() {
+            paths.push("-".to_string());
+        }
 
+        let fixed_strings = Self::find_fixed_strings(&args);
         let case_sensitive = Self::find_case_sensitive(&args);
-        // if case_sensitive is true, ignore_case is false
-        let ignore_case = !case_sensitive && Self::find_ignore_case(&args);
+        let smart_case = Self::find_smart_case(&args);
+        // if case_sensitive is true, ignore_case is false; smart case only
+        // applies when neither -i nor IGNORE_CASE decided
+        let ignore_case = !case_sensitive
+            && Self::find_ignore_case(&args, &env)
+                .unwrap_or_else(|| smart_case && !patterns.iter().any(|p| has_uppercase(p, fixed_strings)));
         let line_number = Self::find_line_number(&args);
-        let replace = Self::find_replace(args);
+        let column = Self::find_column(&args);
+        let byte_offset = Self::find_byte_offset(&args);
+        let invert_match = Self::find_invert_match(&args);
+        let only_matching = Self::find_only_matching(&args);
+        let bounds = Self::find_bounds(&args);
+        let follow_links = Self::find_follow_links(&args);
+        let no_ignore = Self::find_no_ignore(&args);
+        // -A and -B take precedence over -C
+        let context = args.number("context")?.unwrap_or(0);
+        let before_context = args.number("before-context")?.unwrap_or(context);
+        let after_context = args.number("after-context")?.unwrap_or(context);
+        let binary_files = Self::find_binary_files(&args)?;
+        let replace = Self::find_replace(&args);
+        let preserve_case = Self::find_preserve_case(&args);
+        let in_place = Self::find_in_place(&args);
+        let backup_suffix = args.value("backup-suffix").map(String::from);
+        let diff = Self::find_diff(&args);
+        let colors = Self::find_colors(&args, &env)?;
+        let json = Self::find_json(&args);
+        let report = Self::find_report(&args);
+        let threads = Self::find_threads(&args)?;
+        let unordered = Self::find_unordered(&args);
+        let mmap = Self::find_mmap(&args);
+        if in_place && diff {
+            return Err(args::invalid("--in-place and --diff can't be used together".to_string()));
+        }
+        if json && (in_place || diff) {
+            return Err(args::invalid("--json can't be used with --in-place or --diff".to_string()));
+        }
+        if report != Report::Lines && (in_place || diff || json) {
+            return Err(args::invalid(
+                "Counts and file lists can't be used with --in-place, --diff or --json".to_string(),
+            ));
+        }
+        if (in_place || diff) && replace.is_none() {
+            return Err(args::invalid("--in-place and --diff need a --replace text".to_string()));
+        }
+        if (in_place || diff) && paths.iter().any(|path| path == "-") {
+            return Err(args::invalid("Can't rewrite standard input".to_string()));
+        }
+
+        let matcher = Self::build_matcher(&patterns, fixed_strings, ignore_case, bounds)?;
 
         Ok(Config {
-            query,
-            file_path,
+            patterns,
+            paths,
             case_sensitive,
+            smart_case,
             ignore_case,
             line_number,
+            column,
+            byte_offset,
+            invert_match,
+            only_matching,
             replace,
+            preserve_case,
+            in_place,
+            backup_suffix,
+            diff,
+            fixed_strings,
+            bounds,
+            matcher,
+            follow_links,
+            no_ignore,
+            before_context,
+            after_context,
+            binary_files,
+            colors,
+            json,
+            report,
+            threads,
+            unordered,
+            mmap,
+        })
+    }
+
+    // -e and -f take the place of the query argument and can be mixed
+    fn find_patterns(args: &Args) -> Result<Option<Vec<String>>, args::Error> {
+        let mut patterns = Vec::new();
+        let mut given = false;
+
+        for (name, value) in args.all_of(&["pattern", "file"]) {
+            given = true;
+            let value = value.unwrap_or_default();
+            if name == "pattern" {
+                patterns.push(value.to_string());
+                continue;
+            }
+            let contents = fs::read_to_string(value)
+                .map_err(|err| args::invalid(format!("Can't read pattern file `{value}`: {err}")))?;
+            patterns.extend(contents.lines().map(String::from));
+        }
+
+        Ok(given.then_some(patterns))
+    }
+
+    fn build_matcher(
+        patterns: &[String],
+        fixed_strings: bool,
+        ignore_case: bool,
+        bounds: Bounds,
+    ) -> Result<Matcher, args::Error> {
+        Matcher::any_of(patterns, fixed_strings, ignore_case, bounds).map_err(|err| {
+            // point at the pattern that's wrong rather than the combined one
+            let (pattern, err) = patterns
+                .iter()
+                .find_map(|p| Matcher::new(p, fixed_strings, ignore_case, bounds).err().map(|err| (p.clone(), err)))
+                .unwrap_or_else(|| (patterns.join("|"), err));
+            args::invalid(format!("Invalid regular expression `{pattern}`: {err}"))
         })
     }
 
-    fn find_case_sensitive(args: &Vec<String>) -> bool {
-        args.iter().any(|arg| arg == "-s" || arg == "--case-sensitive")
+    fn find_case_sensitive(args: &Args) -> bool {
+        args.flag("case-sensitive")
+    }
+
+    // None when neither IGNORE_CASE nor -i says anything
+    fn find_ignore_case(args: &Args, env: impl Fn(&str) -> Option<OsString>) -> Option<bool> {
+        match env("IGNORE_CASE").and_then(|val| val.into_string().ok()) {
+            Some(val) => Some(val == "1"),
+            None => args.flag("ignore-case").then_some(true),
+        }
+    }
+
+    fn find_smart_case(args: &Args) -> bool {
+        args.flag("smart-case")
     }
 
-    fn find_ignore_case(args: &Vec<String>) -> bool {
-        match env::var("IGNORE_CASE") {
-            Ok(val) => val == "1",
-            Err(_) => args.iter().any(|arg| arg == "-i" || arg == "--ignore-case"),
+    fn find_line_number(args: &Args) -> bool {
+        args.flag("line-number")
+    }
+
+    fn find_column(args: &Args) -> bool {
+        args.flag("column")
+    }
+
+    fn find_byte_offset(args: &Args) -> bool {
+        args.flag("byte-offset")
+    }
+
+    fn find_invert_match(args: &Args) -> bool {
+        args.flag("invert-match")
+    }
+
+    fn find_only_matching(args: &Args) -> bool {
+        args.flag("only-matching")
+    }
+
+    // regex is the default; whichever of -F and -E comes last wins
+    fn find_fixed_strings(args: &Args) -> bool {
+        matches!(args.last_of(&["fixed-strings", "regexp"]), Some(("fixed-strings", _)))
+    }
+
+    // -x is stricter than -w, so it wins when both are given
+    fn find_bounds(args: &Args) -> Bounds {
+        if args.flag("line-regexp") {
+            Bounds::Line
+        } else if args.flag("word-regexp") {
+            Bounds::Word
+        } else {
+            Bounds::Any
+        }
+    }
+
+    fn find_follow_links(args: &Args) -> bool {
+        args.flag("follow")
+    }
+
+    fn find_no_ignore(args: &Args) -> bool {
+        args.flag("no-ignore")
+    }
+
+    // 0, the default, picks one thread per CPU
+    fn find_threads(args: &Args) -> Result<usize, args::Error> {
+        match args.number("threads")? {
+            None | Some(0) => Ok(thread::available_parallelism().map_or(1, |threads| threads.get())),
+            Some(threads) => Ok(threads),
+        }
+    }
+
+    fn find_unordered(args: &Args) -> bool {
+        args.flag("unordered")
+    }
+
+    fn find_mmap(args: &Args) -> MmapChoice {
+        match args.last_of(&["mmap", "no-mmap"]) {
+            None => MmapChoice::Auto,
+            Some(("mmap", _)) => MmapChoice::Always,
+            Some(_) => MmapChoice::Never,
+        }
+    }
+
+    fn find_binary_files(args: &Args) -> Result<BinaryFiles, args::Error> {
+        match args.last_of(&["text", "binary-files"]) {
+            None | Some((_, Some("binary"))) => Ok(BinaryFiles::Binary),
+            Some((_, Some("without-match"))) => Ok(BinaryFiles::WithoutMatch),
+            Some(("text", _)) | Some((_, Some("text"))) => Ok(BinaryFiles::Text),
+            Some((_, other)) => Err(args::invalid(format!(
+                "Unknown binary files type `{}`",
+                other.unwrap_or_default()
+            ))),
+        }
+    }
+
+    fn find_replace(args: &Args) -> Option<String> {
+        args.value("replace").map(str::to_string)
+    }
+
+    fn find_preserve_case(args: &Args) -> bool {
+        args.flag("preserve-case")
+    }
+
+    fn find_in_place(args: &Args) -> bool {
+        args.flag("in-place")
+    }
+
+    fn find_diff(args: &Args) -> bool {
+        args.flag("diff")
+    }
+
+    fn find_json(args: &Args) -> bool {
+        args.flag("json")
+    }
+
+    // whichever of -c, --count-matches, -l and -L comes last wins
+    fn find_report(args: &Args) -> Report {
+        match args.last_of(&["count", "count-matches", "files-with-matches", "files-without-match"]) {
+            None => Report::Lines,
+            Some(("count", _)) => Report::Count,
+            Some(("count-matches", _)) => Report::CountMatches,
+            Some(("files-with-matches", _)) => Report::FilesWithMatches,
+            Some(_) => Report::FilesWithoutMatch,
+        }
+    }
+
+    // --color wins over NO_COLOR, which wins over the terminal check
+    fn find_colors(args: &Args, env: impl Fn(&str) -> Option<OsString>) -> Result<Colors, args::Error> {
+        let choice = match args.value("color") {
+            None => ColorChoice::Auto,
+            Some(value) => ColorChoice::parse(value)
+                .ok_or_else(|| args::invalid(format!("Unknown color choice `{value}`")))?,
+        };
+        let enabled = match choice {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                env("NO_COLOR").is_none_or(|value| value.is_empty())
+                    && env("TERM").is_none_or(|term| term != "dumb")
+                    && io::stdout().is_terminal()
+            }
+        };
+        if !enabled {
+            return Ok(Colors::plain());
+        }
+
+        match env(color::COLORS_ENV).and_then(|spec| spec.into_string().ok()) {
+            Some(spec) => Colors::parse(&spec)
+                .map_err(|err| args::invalid(format!("Invalid {}: {err}", color::COLORS_ENV))),
+            None => Ok(Colors::default()),
         }
     }
+}
 
-    fn find_line_number(args: &Vec<String>) -> bool {
-        args.iter().any(|arg| arg == "-n" || arg == "--line-number")
+/// A line that matched the query, along with where it was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match<'a> {
+    /// 1-based line number in the searched contents.
+    pub line_number: usize,
+    /// Byte offset of the start of the line in the searched contents.
+    pub byte_offset: usize,
+    /// The line without its terminator. It is not necessarily UTF-8.
+    pub line: &'a [u8],
+    /// Where the first match is in `line`. `None` for context lines and
+    /// lines selected because they don't match.
+    pub span: Option<Span>,
+}
+
+impl Match<'_> {
+    /// 1-based column of the first match, counted in characters.
+    pub fn column(&self) -> Option<usize> {
+        let span = self.span?;
+        Some(String::from_utf8_lossy(&self.line[..span.start]).chars().count() + 1)
     }
 
-    fn find_replace(args: Vec<String>) -> String {
-        args.iter()
-            .position(|arg| arg == "-r" || arg == "--replace")
-            .and_then(|pos| args.get(pos + 1))
-            .unwrap_or(&String::new())
-            .clone()
+    /// Byte offset of the first match in the searched contents, or of the
+    /// line when there is no match in it.
+    pub fn match_offset(&self) -> usize {
+        self.byte_offset + self.span.map_or(0, |span| span.start)
     }
 }
 
+/// Byte range of a match within a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A line of output once context has been added around the matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Line<'a> {
+    Matched(Match<'a>),
+    Context(Match<'a>),
+    /// Separates groups of lines that aren't contiguous.
+    Break,
+}
+
 pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let contents = fs::read_to_string(&config.file_path)?;
-    let results = search_based_on_case(&config, &contents);
+    let with_filename = config.paths.len() > 1 || Path::new(&config.paths[0]).is_dir();
+    let mut failed = 0;
+    let mut stats = json::Stats::default();
+
+    let mut jobs = config.paths.iter().flat_map(|path| -> Box<dyn Iterator<Item = Job> + Send + '_> {
+        if path == "-" {
+            return Box::new(std::iter::once(Job::Stdin));
+        }
+        let walk = walk::Walk::new(&[path])
+            .follow_links(config.follow_links)
+            .ignore(!config.no_ignore);
+        Box::new(walk.map(|entry| entry.map_or_else(Job::Failed, Job::File)))
+    });
+    // a single file gains nothing from the pool
+    let (first, second) = (jobs.next(), jobs.next());
+    let several = second.is_some();
+    let jobs = first.into_iter().chain(second).chain(jobs);
+
+    let mut report = |result: io::Result<()>| {
+        if let Err(err) = result {
+            eprintln!("kkjgrep: {err}");
+            failed += 1;
+        }
+    };
+
+    // standard input is searched as it arrives, which buffering would defeat
+    let mut out = if config.threads > 1 && several && !config.paths.iter().any(|path| path == "-") {
+        let pool = parallel::Pool { threads: config.threads, sorted: !config.unordered };
+        let ordered = parallel::Ordered::new(Output::new(io::stdout()));
+        let work = |index, job| {
+            let mut stats = json::Stats::default();
+            if config.unordered {
+                let mut buf = Vec::new();
+                let result = run_job(&config, job, with_filename, &mut stats, &mut buf);
+                return (buf, stats, result);
+            }
+            let mut out = ordered.writer(index);
+            let result = run_job(&config, job, with_filename, &mut stats, &mut out);
+            (Vec::new(), stats, result.and(out.finish()))
+        };
+        pool.run(jobs, work, |(buf, file_stats, result)| {
+            stats.add(&file_stats);
+            if ordered.with_out(|out| out.write_all(&buf).is_err() || out.error.is_some()) {
+                return ControlFlow::Break(());
+            }
+            report(result);
+            ControlFlow::Continue(())
+        });
+        let error = ordered.into_inner().error;
+        Output { inner: io::stdout().lock(), error }
+    } else {
+        let mut out = Output::new(io::stdout().lock());
+        for job in jobs {
+            let result = run_job(&config, job, with_filename, &mut stats, &mut out);
+            if out.error.is_some() {
+                break;
+            }
+            report(result);
+        }
+        out
+    };
 
-    for (i, line) in results.iter().enumerate() {
-        let line = replace_if_not_empty(&config, line);
-        print_based_on_line_number(&config, i, line);
+    if config.json {
+        // a failure is kept in `out` and reported below
+        let _ = json::write_summary(&mut out, &stats);
+    }
+    if let Some(err) = out.error {
+        // whoever reads the output has seen enough, like `head` does
+        if err.kind() == io::ErrorKind::BrokenPipe {
+            return Ok(());
+        }
+        return Err(format!("Can't write output: {err}").into());
+    }
+
+    if failed > 0 {
+        return Err(format!("{failed} path(s) could not be searched").into());
     }
 
     Ok(())
 }
 
-fn print_based_on_line_number(config: &Config, i: usize, line: String) {
+/// Standard output, keeping the first error writing to it. Once writing has
+/// failed, nothing more can be printed, so it's not blamed on whichever file
+/// was being searched.
+struct Output<W> {
+    inner: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Output<W> {
+    fn new(inner: W) -> Output<W> {
+        Output { inner, error: None }
+    }
+
+    fn keep_error<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        result.map_err(|err| {
+            let kind = err.kind();
+            self.error.get_or_insert(err);
+            kind.into()
+        })
+    }
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if let Some(err) = &self.error {
+            return Err(err.kind().into());
+        }
+        let result = self.inner.write(data);
+        self.keep_error(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(err) = &self.error {
+            return Err(err.kind().into());
+        }
+        let result = self.inner.flush();
+        self.keep_error(result)
+    }
+}
+
+/// One input to search, in the order the walk found it.
+enum Job {
+    Stdin,
+    File(PathBuf),
+    /// The walk couldn't get to a path.
+    Failed(io::Error),
+}
+
+fn run_job(
+    config: &Config,
+    job: Job,
+    with_filename: bool,
+    stats: &mut json::Stats,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    match job {
+        Job::Stdin => search_stdin(config, with_filename, stats, out),
+        Job::File(path) if config.in_place => rewrite_file(config, &path, out),
+        Job::File(path) if config.diff => diff_file(config, &path, out),
+        Job::File(path) => search_file(config, &path, with_filename, stats, out),
+        Job::Failed(err) => Err(err),
+    }
+}
+
+const STDIN_NAME: &str = "(standard input)";
+
+fn search_stdin(config: &Config, with_filename: bool, stats: &mut json::Stats, out: &mut impl Write) -> io::Result<()> {
+    let input = Input::Reader(io::stdin().lock());
+    search_source(config, input, STDIN_NAME, with_filename, stats, out)
+        .map_err(|err| walk::with_path(Path::new(STDIN_NAME), err))
+}
+
+fn search_file(
+    config: &Config,
+    path: &Path,
+    with_filename: bool,
+    stats: &mut json::Stats,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let file = File::open(path).map_err(|err| walk::with_path(path, err))?;
+    let name = path.display().to_string();
+    let map = Mmap::for_file(&file, config.mmap);
+    let input = match &map {
+        Some(map) => Input::Slice(map),
+        None => Input::Reader(BufReader::new(file)),
+    };
+
+    search_source(config, input, &name, with_filename, stats, out)
+        .map_err(|err| walk::with_path(path, err))
+}
+
+fn rewrite_file(config: &Config, path: &Path, out: &mut impl Write) -> io::Result<()> {
+    let rewrite = rewrite::Rewrite {
+        matcher: &config.matcher,
+        replacement: config.replace.as_deref().unwrap_or_default(),
+        preserve_case: config.preserve_case,
+        backup_suffix: config.backup_suffix.as_deref(),
+        text: config.binary_files == BinaryFiles::Text,
+    };
+
+    let replacements = rewrite.rewrite_file(path).map_err(|err| walk::with_path(path, err))?;
+    if replacements > 0 {
+        writeln!(out, "{}: {replacements} replacement(s)", path.display())?;
+    }
+
+    Ok(())
+}
+
+fn diff_file(config: &Config, path: &Path, out: &mut impl Write) -> io::Result<()> {
+    let file = File::open(path).map_err(|err| walk::with_path(path, err))?;
+    let mut reader = BufReader::new(file);
+    if config.binary_files != BinaryFiles::Text && reader.fill_buf()?.contains(&0) {
+        return Ok(());
+    }
+
+    let name = path.display().to_string();
+    diff::write_diff(reader, &name, |line| replace_if_given(config, line), out)
+        .map_err(|err| walk::with_path(path, err))?;
+
+    Ok(())
+}
+
+fn search_source(
+    config: &Config,
+    input: Input<impl BufRead>,
+    name: &str,
+    with_filename: bool,
+    stats: &mut json::Stats,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    // lines around a match make no sense when only the match is printed
+    let context = |lines| if config.only_matching { 0 } else { lines };
+    let searcher = Searcher {
+        before_context: context(config.before_context),
+        after_context: context(config.after_context),
+        binary_files: config.binary_files,
+        max_matches: None,
+        invert_match: config.invert_match,
+        literal: config.matcher.required_literal(),
+    };
+    if config.json {
+        return search_json(config, searcher, input, name, stats, out);
+    }
+    if config.report != Report::Lines {
+        return search_report(config, input, name, with_filename, out);
+    }
+
+    let find = |line: &[u8]| find_first(config, line);
+
+    let summary = searcher.search(input, find, |line| {
+        print_line(config, out, name, with_filename, line)
+    })?;
+
+    if summary.binary_match {
+        writeln!(out, "Binary file {name} matches")?;
+    }
+
+    Ok(())
+}
+
+fn search_json(
+    config: &Config,
+    searcher: Searcher,
+    input: Input<impl BufRead>,
+    name: &str,
+    stats: &mut json::Stats,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let find = |line: &[u8]| find_first(config, line);
+    let mut file_stats = json::Stats { searches: 1, ..Default::default() };
+    let mut begun = false;
+    let mut submatches = Vec::new();
+
+    let summary = searcher.search(input, find, |line| {
+        let (m, matched) = match line {
+            Line::Matched(m) => (m, true),
+            Line::Context(m) => (m, false),
+            Line::Break => return Ok(()),
+        };
+        if !begun {
+            json::write_begin(out, name)?;
+            begun = true;
+        }
+
+        submatches.clear();
+        if matched && !config.invert_match {
+            submatches.extend(config.matcher.find_iter(m.line));
+            file_stats.matches += submatches.len();
+        }
+        json::write_line(out, name, &m, matched, &submatches)
+    })?;
+
+    if summary.matched_lines > 0 || summary.binary_match {
+        if !begun {
+            json::write_begin(out, name)?;
+        }
+        file_stats.searches_with_match = 1;
+        file_stats.matched_lines = summary.matched_lines;
+        json::write_end(out, name, summary.binary_match, &file_stats)?;
+    }
+    stats.add(&file_stats);
+
+    Ok(())
+}
+
+fn search_report(
+    config: &Config,
+    input: Input<impl BufRead>,
+    name: &str,
+    with_filename: bool,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let searcher = Searcher {
+        // no lines are printed, so binary files can be searched like text
+        binary_files: match config.binary_files {
+            BinaryFiles::Binary => BinaryFiles::Text,
+            other => other,
+        },
+        // one match is enough to know whether the file gets listed
+        max_matches: match config.report {
+            Report::FilesWithMatches | Report::FilesWithoutMatch => Some(1),
+            _ => None,
+        },
+        invert_match: config.invert_match,
+        literal: config.matcher.required_literal(),
+        ..Searcher::default()
+    };
+    let find = |line: &[u8]| find_first(config, line);
+    let mut matches = 0;
+
+    let summary = searcher.search(input, find, |line| {
+        if let (Report::CountMatches, Line::Matched(m)) = (config.report, line) {
+            // an inverted match has nothing to count but the line itself
+            matches += if config.invert_match { 1 } else { config.matcher.find_iter(m.line).count() };
+        }
+        Ok(())
+    })?;
+
+    let colors = &config.colors;
+    let count = match config.report {
+        Report::Count => summary.matched_lines,
+        Report::CountMatches => matches,
+        Report::FilesWithMatches | Report::FilesWithoutMatch => {
+            if (summary.matched_lines > 0) == (config.report == Report::FilesWithMatches) {
+                color::paint(out, &colors.path, name.as_bytes())?;
+                out.write_all(b"\n")?;
+            }
+            return Ok(());
+        }
+        Report::Lines => unreachable!("lines are printed by search_source"),
+    };
+
+    if with_filename {
+        color::paint(out, &colors.path, name.as_bytes())?;
+        color::paint(out, &colors.separator, b":")?;
+    }
+    writeln!(out, "{count}")
+}
+
+fn print_line(config: &Config, out: &mut impl Write, path: &str, with_filename: bool, line: Line) -> io::Result<()> {
+    match line {
+        Line::Matched(m) if config.only_matching => return print_only_matching(config, out, path, with_filename, &m),
+        Line::Matched(m) => {
+            print_based_on_line_number(config, out, path, with_filename, &m, ':')?;
+            print_matched(config, out, m.line)?;
+        }
+        Line::Context(m) => {
+            print_based_on_line_number(config, out, path, with_filename, &m, '-')?;
+            out.write_all(m.line)?;
+        }
+        Line::Break => color::paint(out, &config.colors.separator, b"--")?,
+    }
+    out.write_all(b"\n")
+}
+
+fn print_based_on_line_number(
+    config: &Config,
+    out: &mut impl Write,
+    path: &str,
+    with_filename: bool,
+    result: &Match,
+    separator: char,
+) -> io::Result<()> {
+    let colors = &config.colors;
+    let separator = separator.to_string();
+
+    if config.line_number || with_filename {
+        color::paint(out, &colors.path, path.as_bytes())?;
+        color::paint(out, &colors.separator, separator.as_bytes())?;
+    }
     if config.line_number {
-        println!("{}: {}", i + 1, line);
-    } else {
-        println!("{}", line);
+        color::paint(out, &colors.line_number, result.line_number.to_string().as_bytes())?;
+        color::paint(out, &colors.separator, separator.as_bytes())?;
     }
+    if let Some(column) = result.column().filter(|_| config.column) {
+        color::paint(out, &colors.line_number, column.to_string().as_bytes())?;
+        color::paint(out, &colors.separator, separator.as_bytes())?;
+    }
+    if config.byte_offset {
+        color::paint(out, &colors.line_number, result.match_offset().to_string().as_bytes())?;
+        color::paint(out, &colors.separator, separator.as_bytes())?;
+    }
+
+    Ok(())
 }
 
-fn replace_if_not_empty(config: &Config, line: &&str) -> String {
-    if !config.replace.is_empty() {
-        replace(&config.query, &config.replace, line)
-    } else {
-        line.to_string()
+// writes a matched line with replacements made and every match colored
+fn print_matched(config: &Config, out: &mut impl Write, line: &[u8]) -> io::Result<()> {
+    let sgr = &config.colors.matched;
+    if sgr.is_empty() {
+        return out.write_all(&replace_if_given(config, line));
     }
+
+    let mut last = 0;
+    for (start, end) in config.matcher.find_iter(line) {
+        out.write_all(&line[last..start])?;
+        let text = replacement_for(config, &line[start..end]);
+        if !text.is_empty() {
+            color::paint(out, sgr, &text)?;
+        }
+        last = end;
+    }
+    out.write_all(&line[last..])
 }
 
-fn search_based_on_case<'a>(config: &'a Config, contents: &'a String) -> Vec<&'a str>  {
-    if config.ignore_case {
-        search_case_insensitive(&config.query, &contents)
-    } else {
-        search(&config.query, &contents)
+// writes each non-empty match of a line on a line of its own
+fn print_only_matching(
+    config: &Config,
+    out: &mut impl Write,
+    path: &str,
+    with_filename: bool,
+    result: &Match,
+) -> io::Result<()> {
+    for (start, end) in config.matcher.find_iter(result.line) {
+        if start == end {
+            continue;
+        }
+        let m = Match { span: Some(Span { start, end }), ..*result };
+        print_based_on_line_number(config, out, path, with_filename, &m, ':')?;
+        color::paint(out, &config.colors.matched, &replacement_for(config, &result.line[start..end]))?;
+        out.write_all(b"\n")?;
     }
+
+    Ok(())
 }
 
-fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
-    contents
-        .lines()
-        .filter(|line| line.contains(query))
-        .collect()
+// what a match is printed as: itself, or the replacement text
+fn replacement_for<'a>(config: &'a Config, matched: &'a [u8]) -> Cow<'a, [u8]> {
+    match &config.replace {
+        None => Cow::Borrowed(matched),
+        Some(replace) if config.preserve_case => {
+            Cow::Owned(with_case_of(&String::from_utf8_lossy(matched), replace).into_bytes())
+        }
+        Some(replace) => Cow::Borrowed(replace.as_bytes()),
+    }
+}
+
+fn replace_if_given<'a>(config: &Config, line: &'a [u8]) -> Cow<'a, [u8]> {
+    match &config.replace {
+        None => Cow::Borrowed(line),
+        Some(replace) => Cow::Owned(config.matcher.replace_all(line, replace, config.preserve_case)),
+    }
+}
+
+// whether a pattern asks for case-sensitive matching under -S; escapes
+// like \W and \S in a regex don't count
+fn has_uppercase(pattern: &str, fixed_strings: bool) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' && !fixed_strings {
+            chars.next();
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+    false
+}
+
+fn find_first(config: &Config, line: &[u8]) -> Option<Span> {
+    config.matcher.find_at(line, 0).map(|(start, end)| Span { start, end })
+}
+
+pub fn search<'a>(query: &str, contents: &'a str, bounds: Bounds) -> Result<Vec<Match<'a>>, regex::Error> {
+    let matcher = Matcher::new(query, true, false, bounds)?;
+    Ok(matches_in(contents, |line| matcher.find_at(line, 0)))
 }
 
-fn search_case_insensitive<'a>(
+pub fn search_case_insensitive<'a>(
     query: &str,
     contents: &'a str,
-) -> Vec<&'a str> {
-    let query = query.to_lowercase();
+    bounds: Bounds,
+) -> Result<Vec<Match<'a>>, regex::Error> {
+    let matcher = Matcher::new(query, true, true, bounds)?;
+    Ok(matches_in(contents, |line| matcher.find_at(line, 0)))
+}
 
-    contents
-        .lines()
-        .filter(|line| line.to_lowercase().contains(&query))
+pub fn search_regex<'a>(regex: &Regex, contents: &'a str) -> Vec<Match<'a>> {
+    matches_in(contents, |line| regex.find_at(line, 0))
+}
+
+// the lines of `contents` that `find` finds a match in
+fn matches_in<'a>(contents: &'a str, find: impl Fn(&[u8]) -> Option<(usize, usize)>) -> Vec<Match<'a>> {
+    lines(contents.as_bytes())
+        .filter_map(|m| {
+            let (start, end) = find(m.line)?;
+            Some(Match { span: Some(Span { start, end }), ..m })
+        })
         .collect()
 }
 
-fn replace<'a>(query: &str, replace: &str, contents: &'a str, ) -> String {
-    contents.replace(query, replace)
+// like str::lines, but keeps track of where each line came from
+fn lines(contents: &[u8]) -> impl Iterator<Item = Match<'_>> {
+    let mut byte_offset = 0;
+
+    contents
+        .split_inclusive(|&b| b == b'\n')
+        .enumerate()
+        .map(move |(i, raw)| {
+            let line = raw.strip_suffix(b"\n").unwrap_or(raw);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let m = Match { line_number: i + 1, byte_offset, line, span: None };
+            byte_offset += raw.len();
+            m
+        })
 }
 
 #[cfg(test)]
@@ -140,7 +968,7 @@ Rust:
 safe, fast, productive.
 Pick three.";
 
-        assert_eq!(vec!["safe, fast, productive."], search(query, contents));
+        assert_eq!(vec!["safe, fast, productive."], lines_of(search(query, contents, Bounds::Any).unwrap()));
     }
 
     #[test]
@@ -152,7 +980,7 @@ safe, fast, productive.
 Pick three.
 Duct tape.";
 
-        assert_eq!(vec!["safe, fast, productive."], search(query, contents));
+        assert_eq!(vec!["safe, fast, productive."], lines_of(search(query, contents, Bounds::Any).unwrap()));
     }
 
     #[test]
@@ -166,8 +994,210 @@ Trust me.";
 
         assert_eq!(
             vec!["Rust:", "Trust me."],
-            search_case_insensitive(query, contents)
+            lines_of(search_case_insensitive(query, contents, Bounds::Any).unwrap())
         );
     }
+
+    #[test]
+    fn source_positions() {
+        let query = "duct";
+        let contents = "\
+Rust:\r
+safe, fast, productive.
+Pick three.
+Duct tape, productive too.";
+
+        assert_eq!(
+            vec![
+                Match {
+                    line_number: 2,
+                    byte_offset: 7,
+                    line: "safe, fast, productive.".as_bytes(),
+                    span: Some(Span { start: 15, end: 19 }),
+                },
+                Match {
+                    line_number: 4,
+                    byte_offset: 43,
+                    line: "Duct tape, productive too.".as_bytes(),
+                    span: Some(Span { start: 14, end: 18 }),
+                },
+            ],
+            search(query, contents, Bounds::Any).unwrap()
+        );
+    }
+
+    #[test]
+    fn whole_words_and_lines() {
+        let contents = "\
+valid identity
+android: id
+id";
+
+        assert_eq!(vec!["android: id", "id"], lines_of(search("id", contents, Bounds::Word).unwrap()));
+        assert_eq!(vec!["id"], lines_of(search_case_insensitive("ID", contents, Bounds::Line).unwrap()));
+        assert_eq!(vec!["valid identity"], lines_of(search_case_insensitive("Valid", contents, Bounds::Word).unwrap()));
+    }
+
+    #[test]
+    fn columns_and_offsets() {
+        let contents = "Rust:\nİstanbul, ſtraße STRASSE\n";
+        let found = search_case_insensitive("strasse", contents, Bounds::Any).unwrap();
+
+        assert_eq!(Some(Span { start: 11, end: 19 }), found[0].span);
+        assert_eq!(Some(11), found[0].column());
+        assert_eq!(17, found[0].match_offset());
+
+        let found = search_case_insensitive("i̇stan", contents, Bounds::Any).unwrap();
+        assert_eq!(Some(Span { start: 0, end: 6 }), found[0].span);
+        assert_eq!(Some(1), found[0].column());
+    }
+
+    #[test]
+    fn regex_search() {
+        let regex = Regex::new(r"^\w+:$|thr.e").unwrap();
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Trust me:";
+
+        assert_eq!(vec!["Rust:", "Pick three."], lines_of(search_regex(&regex, contents)));
+    }
+
+    #[test]
+    fn invalid_regex() {
+        let err = build(&["(duct", "poem.txt"]).unwrap_err().to_string();
+
+        assert!(err.contains("unclosed group"), "{err}");
+    }
+
+    #[test]
+    fn fixed_strings() {
+        let config = build(&["-F", "(duct", "poem.txt"]).unwrap();
+
+        assert!(config.fixed_strings);
+        assert!(matches!(config.matcher, Matcher::Literal(_)));
+    }
+
+    #[test]
+    fn multiple_patterns() {
+        let path = std::env::temp_dir().join(format!("kkjgrep-patterns-{}", std::process::id()));
+        fs::write(&path, "nobody\r\nfrog\n").unwrap();
+        let list = path.to_str().unwrap();
+        let err = build(&["-e", "dreary", "-f", list, "-e", "(bad", "poem.txt"]).unwrap_err().to_string();
+        assert!(err.contains("`(bad`"), "{err}");
+
+        let config = build(&["-e", "dreary", "-f", list, "poem.txt"]).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(vec!["dreary", "nobody", "frog"], config.patterns);
+        assert_eq!(vec!["poem.txt"], config.paths);
+        assert!(matches!(config.matcher, Matcher::Literals(_)));
+    }
+
+    #[test]
+    fn only_matching() {
+        let config = build(&["-on", "--color=never", "[a-z]*id", "-"]).unwrap();
+        let m = Match { line_number: 3, byte_offset: 0, line: b"user_id, uuid and id", span: None };
+        let mut out = Vec::new();
+        print_line(&config, &mut out, "log", false, Line::Matched(m)).unwrap();
+
+        assert_eq!("log:3:id\nlog:3:uuid\nlog:3:id\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn empty_replacement() {
+        let config = build(&["--color=never", "-r", "", "--in-place", "debug! ", "src"]).unwrap();
+        let m = Match { line_number: 1, byte_offset: 0, line: b"debug! x; debug! y", span: None };
+        let mut out = Vec::new();
+        print_line(&config, &mut out, "log", false, Line::Matched(m)).unwrap();
+
+        assert_eq!(Some(""), config.replace.as_deref());
+        assert_eq!("x; y\n", String::from_utf8(out).unwrap());
+        assert!(build(&["--diff", "debug! ", "src"]).is_err());
+    }
+
+    #[test]
+    fn smart_case() {
+        let ignore_case = |args: &[&str]| build(&[&["-S"], args].concat()).unwrap().ignore_case;
+
+        assert!(ignore_case(&[r"\Wrust\S"]));
+        assert!(!ignore_case(&["Rust"]));
+        assert!(!ignore_case(&["-e", "rust", "-e", "Go"]));
+        assert!(ignore_case(&["-i", "Rust"]));
+        assert!(!ignore_case(&["-s", "rust"]));
+        assert!(!ignore_case(&["-F", r"\W"]));
+        assert!(!has_uppercase(r"\D\B", false));
+
+        // IGNORE_CASE decides before -i and -S do
+        let with_env = |value: &str, query: &str| {
+            let args = ["kkjgrep", "-S", "-i", query].map(String::from).into_iter();
+            Config::build_with_env(args, |name| (name == "IGNORE_CASE").then(|| value.into())).unwrap().ignore_case
+        };
+        assert!(with_env("1", "Rust"));
+        assert!(!with_env("0", "rust"));
+    }
+
+    #[test]
+    fn stdin_by_default() {
+        let config = build(&["duct", "-n"]).unwrap();
+
+        assert_eq!(vec!["-"], config.paths);
+        assert!(config.line_number);
+    }
+
+    #[test]
+    fn threads() {
+        let config = build(&["-j", "3", "duct"]).unwrap();
+        assert_eq!(3, config.threads);
+        assert!(!config.unordered);
+        assert!(build(&["--unordered", "duct"]).unwrap().unordered);
+        assert!(build(&["-j0", "duct"]).unwrap().threads >= 1);
+        assert!(build(&["--threads=many", "duct"]).is_err());
+    }
+
+    #[test]
+    fn color_choice() {
+        let colors = |color: &str| build(&[color, "duct"]).map(|config| config.colors);
+
+        assert_eq!(Colors::plain(), colors("--color=never").unwrap());
+        assert_eq!("1;31", colors("--color=always").unwrap().matched);
+        assert!(colors("--color=sometimes").is_err());
+
+        let env = |name: &str| match name {
+            "NO_COLOR" => Some("1".into()),
+            color::COLORS_ENV => Some("match=4".into()),
+            _ => None,
+        };
+        let with_env = |color: &str| Config::build_with_env(["kkjgrep", color, "duct"].map(String::from).into_iter(), env);
+        assert_eq!(Colors::plain(), with_env("--color=auto").unwrap().colors);
+        assert_eq!("4", with_env("--color=always").unwrap().colors.matched);
+    }
+
+    #[test]
+    fn report_modes() {
+        assert_eq!(Report::Lines, build(&["duct"]).unwrap().report);
+        assert_eq!(Report::FilesWithoutMatch, build(&["-lcL", "duct"]).unwrap().report);
+        assert_eq!(Report::CountMatches, build(&["-c", "--count-matches", "duct"]).unwrap().report);
+        assert!(build(&["-l", "--json", "duct"]).is_err());
+    }
+
+    #[test]
+    fn invert_match() {
+        let config = build(&["-vi", "RUST", "poem.txt"]).unwrap();
+
+        assert!(config.invert_match);
+        assert_eq!(Some(Span { start: 1, end: 5 }), find_first(&config, b"Trust me."));
+    }
+
+    fn lines_of<'a>(results: Vec<Match<'a>>) -> Vec<&'a str> {
+        results.iter().map(|m| std::str::from_utf8(m.line).unwrap()).collect()
+    }
+
+    // builds a config from `args` as if no environment variable were set
+    fn build(args: &[&str]) -> Result<Config, args::Error> {
+        let args = ["kkjgrep"].iter().chain(args).map(|arg| arg.to_string());
+        Config::build_with_env(args, |_| None)
+    }
 }
 
@@ -1,12 +1,27 @@
 use std::env;
+use std::io::{self, Write};
 use std::process;
 
+use kkjgrep::args;
 use kkjgrep::Config;
 
 fn main() {
-    let config = Config::build(env::args()).unwrap_or_else(|err| {
-        eprintln!("Problem parsing arguments: {err}");
-        process::exit(1);
+    let config = Config::build(env::args()).unwrap_or_else(|err| match err {
+        args::Error::Help(text) | args::Error::Version(text) => {
+            // like a search, stop quietly when the reader has gone away
+            match writeln!(io::stdout(), "{}", text.trim_end()) {
+                Err(err) if err.kind() != io::ErrorKind::BrokenPipe => {
+                    eprintln!("Application error: Can't write output: {err}");
+                    process::exit(1);
+                }
+                _ => process::exit(0),
+            }
+        }
+        args::Error::Invalid(err) => {
+            eprintln!("Problem parsing arguments: {err}");
+            eprintln!("Try `kkjgrep --help` for more information.");
+            process::exit(1);
+        }
     });
 
 
@@ -14,4 +29,4 @@ fn main() {
         eprintln!("Application error: {e}");
         process::exit(1);
     }
-}
\ No newline at end of file
+}
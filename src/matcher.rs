@@ -0,0 +1,264 @@
+//! The compiled form of the query, shared by searching and replacing so
+//! both always agree on what a match is.
+
+use crate::aho_corasick::Literals;
+use crate::casefold::Needle;
+use crate::memchr::Finder;
+use crate::regex::{self, Bounds, Regex};
+
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    /// A case-sensitive fixed string that can match anywhere, or a regex
+    /// that is nothing more than one.
+    Literal(Finder),
+    /// A case-insensitive fixed string, or a regex that is nothing more
+    /// than one, compared with full case folding.
+    Folded(Needle),
+    /// Several non-empty fixed strings, or none at all, which never match.
+    Literals(Box<Literals>),
+    /// A regular expression. Case-sensitive fixed strings with `-w` or `-x`
+    /// are compiled to an escaped regex as well.
+    Regex(Regex),
+}
+
+impl Matcher {
+    pub fn new(query: &str, fixed_strings: bool, ignore_case: bool, bounds: Bounds) -> Result<Matcher, regex::Error> {
+        let literal = fixed_strings || regex::escape(query) == query;
+        let pattern = match (literal, ignore_case, bounds) {
+            (true, false, Bounds::Any) => return Ok(Matcher::Literal(Finder::new(query.as_bytes()))),
+            (true, true, _) => return Ok(Matcher::Folded(Needle::new(query, bounds))),
+            _ if fixed_strings => regex::escape(query),
+            _ => query.to_string(),
+        };
+
+        Ok(Matcher::Regex(Regex::with_bounds(&pattern, ignore_case, bounds)?))
+    }
+
+    /// Matches wherever any of `patterns` does. Plain strings are searched
+    /// for all at once; anything else becomes one regex alternation.
+    pub fn any_of(
+        patterns: &[String],
+        fixed_strings: bool,
+        ignore_case: bool,
+        bounds: Bounds,
+    ) -> Result<Matcher, regex::Error> {
+        match patterns {
+            // an empty automaton, which never matches
+            [] => return Ok(Matcher::Literals(Box::new(Literals::new(patterns, false, Bounds::Any)))),
+            [pattern] => return Matcher::new(pattern, fixed_strings, ignore_case, bounds),
+            _ => {}
+        }
+
+        let literal = |pattern: &String| fixed_strings || regex::escape(pattern) == *pattern;
+        if patterns.iter().all(|p| !p.is_empty() && literal(p)) {
+            return Ok(Matcher::Literals(Box::new(Literals::new(patterns, ignore_case, bounds))));
+        }
+
+        let alternation: Vec<String> = patterns
+            .iter()
+            .map(|pattern| {
+                if fixed_strings {
+                    format!("(?:{})", regex::escape(pattern))
+                } else {
+                    format!("(?:{pattern})")
+                }
+            })
+            .collect();
+        Ok(Matcher::Regex(Regex::with_bounds(&alternation.join("|"), ignore_case, bounds)?))
+    }
+
+    /// Byte range of the first match at or after `start`.
+    pub fn find_at(&self, haystack: &[u8], start: usize) -> Option<(usize, usize)> {
+        match self {
+            Matcher::Literal(finder) => {
+                let pos = finder.find(&haystack[start..])?;
+                Some((start + pos, start + pos + finder.needle().len()))
+            }
+            Matcher::Folded(needle) => needle.find_at(haystack, start),
+            Matcher::Literals(literals) => literals.find_at(haystack, start),
+            Matcher::Regex(regex) => regex.find_at(haystack, start),
+        }
+    }
+
+    /// A string that every match contains, so input without it can be
+    /// skipped without looking at its lines.
+    pub fn required_literal(&self) -> Option<&Finder> {
+        match self {
+            Matcher::Literal(finder) => Some(finder),
+            _ => None,
+        }
+    }
+
+    pub fn is_match(&self, haystack: &[u8]) -> bool {
+        self.find_at(haystack, 0).is_some()
+    }
+
+    /// Iterates over successive non-overlapping matches.
+    pub fn find_iter<'m, 'h>(&'m self, haystack: &'h [u8]) -> Matches<'m, 'h> {
+        Matches { matcher: self, haystack, pos: 0, last_end: None }
+    }
+
+    /// Replaces every match in `haystack`. With `preserve_case`, the
+    /// replacement takes on the case pattern of the text it replaces.
+    pub fn replace_all(&self, haystack: &[u8], replacement: &str, preserve_case: bool) -> Vec<u8> {
+        let mut out = Vec::with_capacity(haystack.len());
+        self.replace_into(haystack, replacement, preserve_case, &mut out);
+        out
+    }
+
+    /// Like [`Matcher::replace_all`], but appends to `out` and returns the
+    /// number of replacements made.
+    pub fn replace_into(&self, haystack: &[u8], replacement: &str, preserve_case: bool, out: &mut Vec<u8>) -> usize {
+        let mut last = 0;
+        let mut count = 0;
+
+        for (start, end) in self.find_iter(haystack) {
+            out.extend_from_slice(&haystack[last..start]);
+            if preserve_case {
+                let matched = String::from_utf8_lossy(&haystack[start..end]);
+                out.extend_from_slice(with_case_of(&matched, replacement).as_bytes());
+            } else {
+                out.extend_from_slice(replacement.as_bytes());
+            }
+            last = end;
+            count += 1;
+        }
+        out.extend_from_slice(&haystack[last..]);
+
+        count
+    }
+}
+
+pub struct Matches<'m, 'h> {
+    matcher: &'m Matcher,
+    haystack: &'h [u8],
+    pos: usize,
+    last_end: Option<usize>,
+}
+
+impl Iterator for Matches<'_, '_> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        loop {
+            if self.pos > self.haystack.len() {
+                return None;
+            }
+            let (start, end) = self.matcher.find_at(self.haystack, self.pos)?;
+
+            if start == end {
+                // step over empty matches so the iterator always advances
+                self.pos = end + regex::decode(self.haystack, end).map_or(1, |(_, len)| len);
+                if self.last_end == Some(end) {
+                    continue;
+                }
+            } else {
+                self.pos = end;
+            }
+            self.last_end = Some(end);
+
+            return Some((start, end));
+        }
+    }
+}
+
+/// Adapts `replacement` to the case of `matched`: all caps stay all caps,
+/// a capitalized word stays capitalized and lowercase stays lowercase.
+/// Anything else gets the replacement as written.
+pub fn with_case_of(matched: &str, replacement: &str) -> String {
+    let cased: Vec<char> = matched.chars().filter(|c| c.is_lowercase() || c.is_uppercase()).collect();
+
+    if cased.len() > 1 && cased.iter().all(|c| c.is_uppercase()) {
+        replacement.to_uppercase()
+    } else if cased.first().is_some_and(|c| c.is_uppercase())
+        && cased[1..].iter().all(|c| c.is_lowercase())
+    {
+        let mut chars = replacement.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => String::new(),
+        }
+    } else if !cased.is_empty() && cased.iter().all(|c| c.is_lowercase()) {
+        replacement.to_lowercase()
+    } else {
+        replacement.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replace(query: &str, fixed_strings: bool, ignore_case: bool, line: &str, preserve_case: bool) -> String {
+        let matcher = Matcher::new(query, fixed_strings, ignore_case, Bounds::Any).unwrap();
+        String::from_utf8(matcher.replace_all(line.as_bytes(), "go", preserve_case)).unwrap()
+    }
+
+    #[test]
+    fn replacement_follows_the_search_mode() {
+        assert_eq!("go, Rust, RUST", replace("rust", true, false, "rust, Rust, RUST", false));
+        assert_eq!("go, go, go", replace("rust", true, true, "rust, Rust, RUST", false));
+        assert_eq!("go, go, go", replace("r.st", false, true, "rust, Rust, RUST", false));
+        assert_eq!("rust go", replace("r.st", true, true, "rust R.ST", false));
+    }
+
+    #[test]
+    fn plain_case_insensitive_queries_fold_fully() {
+        for fixed_strings in [true, false] {
+            let matcher = Matcher::new("strasse", fixed_strings, true, Bounds::Any).unwrap();
+            assert!(matches!(matcher, Matcher::Folded(_)));
+            assert_eq!(Some((4, 11)), matcher.find_at("the STRAßE".as_bytes(), 0));
+        }
+        assert!(matches!(Matcher::new("stras+e", false, true, Bounds::Any).unwrap(), Matcher::Regex(_)));
+    }
+
+    #[test]
+    fn replacement_honors_bounds() {
+        let matcher = Matcher::new("id", true, false, Bounds::Word).unwrap();
+        assert_eq!(b"valid go id_id".to_vec(), matcher.replace_all(b"valid id id_id", "go", false));
+
+        let matcher = Matcher::new("i.", false, true, Bounds::Line).unwrap();
+        assert_eq!(b"go".to_vec(), matcher.replace_all(b"ID", "go", false));
+        assert_eq!(b"IDs".to_vec(), matcher.replace_all(b"IDs", "go", false));
+    }
+
+    #[test]
+    fn any_of() {
+        let patterns = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let any_of = |list: &[&str], fixed_strings, ignore_case| {
+            Matcher::any_of(&patterns(list), fixed_strings, ignore_case, Bounds::Any).unwrap()
+        };
+
+        let matcher = any_of(&["user_id", "name", "a.b"], true, false);
+        assert!(matches!(matcher, Matcher::Literals(_)));
+        assert_eq!(vec![(0, 4), (5, 12), (13, 16)], matcher.find_iter(b"name user_id a.b axb").collect::<Vec<_>>());
+
+        let matcher = any_of(&["user_id", "a.b"], false, false);
+        assert!(matches!(matcher, Matcher::Regex(_)));
+        assert_eq!(vec![(0, 3)], matcher.find_iter(b"axb USER_ID").collect::<Vec<_>>());
+
+        let matcher = any_of(&["user_id", "a.b"], true, true);
+        assert!(matches!(matcher, Matcher::Literals(_)));
+        assert_eq!(vec![(4, 11)], matcher.find_iter(b"axb USER_ID").collect::<Vec<_>>());
+        assert!(Matcher::any_of(&patterns(&["ok", "(bad"]), false, false, Bounds::Any).is_err());
+
+        let matcher = Matcher::any_of(&patterns(&["id", "name"]), false, true, Bounds::Word).unwrap();
+        assert!(matches!(matcher, Matcher::Literals(_)));
+        assert_eq!(vec![(7, 9)], matcher.find_iter(b"valid; ID, ids").collect::<Vec<_>>());
+
+        // no patterns match nothing, whatever the flags
+        for (ignore_case, bounds) in [(false, Bounds::Any), (true, Bounds::Any), (false, Bounds::Word), (true, Bounds::Line)] {
+            let matcher = Matcher::any_of(&[], false, ignore_case, bounds).unwrap();
+            assert!(matcher.find_at(b"anything", 0).is_none());
+            assert!(matcher.find_at(b"", 0).is_none());
+        }
+    }
+
+    #[test]
+    fn preserve_case() {
+        assert_eq!("go, Go, GO, go", replace("rust", true, true, "rust, Rust, RUST, rUsT", true));
+        assert_eq!("Go", with_case_of("R", "go"));
+        assert_eq!("goLang", with_case_of("rUsT", "goLang"));
+        assert_eq!("Go-Lang", with_case_of("Rust", "go-Lang"));
+    }
+}
@@ -0,0 +1,152 @@
+//! Fast byte and substring search.
+//!
+//! [`memchr`] compares 16 bytes at a time with SSE2 on x86-64, which every
+//! x86-64 CPU has, and a word at a time elsewhere. [`Finder`] builds on it:
+//! it looks for the needle's rarest byte with `memchr` and only compares
+//! the whole needle where that byte turns up, so most of the haystack is
+//! only ever touched by the vectorized loop.
+
+/// Position of the first `byte` in `haystack`.
+pub fn memchr(byte: u8, haystack: &[u8]) -> Option<usize> {
+    imp::memchr(byte, haystack)
+}
+
+/// Position of the first occurrence of `needle` in `haystack`.
+pub fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    find_with(haystack, needle, rarest(needle))
+}
+
+/// A needle prepared for searching many haystacks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finder {
+    needle: Vec<u8>,
+    // index of the byte that's searched for first
+    rare: usize,
+}
+
+impl Finder {
+    pub fn new(needle: &[u8]) -> Finder {
+        Finder { needle: needle.to_vec(), rare: rarest(needle) }
+    }
+
+    pub fn needle(&self) -> &[u8] {
+        &self.needle
+    }
+
+    /// Position of the first occurrence of the needle in `haystack`.
+    pub fn find(&self, haystack: &[u8]) -> Option<usize> {
+        find_with(haystack, &self.needle, self.rare)
+    }
+}
+
+fn find_with(haystack: &[u8], needle: &[u8], rare: usize) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    if haystack.len() < needle.len() {
+        return None;
+    }
+
+    // matches can't start past this
+    let last = haystack.len() - needle.len();
+    let mut start = 0;
+    while start <= last {
+        let hit = memchr(needle[rare], &haystack[start + rare..=last + rare])?;
+        let candidate = start + hit;
+        if &haystack[candidate..candidate + needle.len()] == needle {
+            return Some(candidate);
+        }
+        start = candidate + 1;
+    }
+    None
+}
+
+fn rarest(needle: &[u8]) -> usize {
+    (0..needle.len()).min_by_key(|&i| frequency(needle[i])).unwrap_or(0)
+}
+
+// rough ranking of how common a byte is in source code and prose, higher
+// being more common
+fn frequency(byte: u8) -> u8 {
+    const COMMON: &[u8] = b"zqjxkvbywgpfmucdlhrsnioate ";
+    match COMMON.iter().position(|&b| b == byte) {
+        Some(rank) => 128 + rank as u8,
+        None if byte.is_ascii_alphanumeric() || byte.is_ascii_punctuation() => 64,
+        None => 0,
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod imp {
+    use std::arch::x86_64::{__m128i, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_set1_epi8};
+
+    pub fn memchr(byte: u8, haystack: &[u8]) -> Option<usize> {
+        let mut chunks = haystack.chunks_exact(16);
+        // SAFETY: SSE2 is part of the x86-64 baseline, and every load reads
+        // exactly the 16 bytes of one chunk
+        unsafe {
+            let wanted = _mm_set1_epi8(byte as i8);
+            for (i, chunk) in chunks.by_ref().enumerate() {
+                let bytes = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+                let mask = _mm_movemask_epi8(_mm_cmpeq_epi8(bytes, wanted));
+                if mask != 0 {
+                    return Some(i * 16 + mask.trailing_zeros() as usize);
+                }
+            }
+        }
+        let rest = chunks.remainder();
+        let offset = haystack.len() - rest.len();
+        rest.iter().position(|&b| b == byte).map(|pos| offset + pos)
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod imp {
+    const WORD: usize = std::mem::size_of::<usize>();
+    const LOW: usize = usize::MAX / 255;
+    const HIGH: usize = LOW << 7;
+
+    pub fn memchr(byte: u8, haystack: &[u8]) -> Option<usize> {
+        let wanted = LOW * byte as usize;
+        let mut chunks = haystack.chunks_exact(WORD);
+        for (i, chunk) in chunks.by_ref().enumerate() {
+            let word = usize::from_ne_bytes(chunk.try_into().unwrap()) ^ wanted;
+            // a zero byte in word is a byte that equals `byte`
+            if word.wrapping_sub(LOW) & !word & HIGH != 0 {
+                let pos = chunk.iter().position(|&b| b == byte).unwrap();
+                return Some(i * WORD + pos);
+            }
+        }
+        let rest = chunks.remainder();
+        let offset = haystack.len() - rest.len();
+        rest.iter().position(|&b| b == byte).map(|pos| offset + pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_bytes_at_every_position() {
+        let haystack: Vec<u8> = (0..100).collect();
+
+        for (i, &b) in haystack.iter().enumerate() {
+            assert_eq!(Some(i), memchr(b, &haystack));
+            assert_eq!(Some(0), memchr(b, &haystack[i..]));
+        }
+        assert_eq!(None, memchr(200, &haystack));
+        assert_eq!(None, memchr(0, &[]));
+    }
+
+    #[test]
+    fn finds_substrings() {
+        assert_eq!(Some(11), find(b"pick a crate, crate name", b"e, c"));
+        assert_eq!(Some(7), find(b"pick a crate, crate name", b"crate"));
+        assert_eq!(Some(20), find(b"zzzzzzzzzzzzzzzzzzzzzq", b"zq"));
+        assert_eq!(Some(0), find(b"abc", b""));
+        assert_eq!(None, find(b"ab", b"abc"));
+        assert_eq!(None, find(b"a crat", b"crate"));
+        assert_eq!(Some(3), find(b"\xff\xfe\x00\xff\x00", b"\xff\x00"));
+    }
+}
@@ -0,0 +1,139 @@
+//! Memory-mapped reading of large files.
+//!
+//! Mapping a file lets the searcher match lines where they sit in the page
+//! cache instead of copying them through a read buffer first. Setting up a
+//! mapping costs more than a few reads, so it's only worth it for larger
+//! files, and it's only possible for regular files: pipes, sockets and
+//! devices are always read the usual way.
+//!
+//! Mappings are made with `mmap(2)` directly, so they're only available on
+//! Unix; elsewhere every file is read.
+//!
+//! A mapped file that is truncated while it's searched makes the process
+//! crash with `SIGBUS`, which is why `--no-mmap` exists.
+
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+
+/// Files at least this large are mapped unless `--no-mmap` is given.
+pub const THRESHOLD: u64 = 1 << 20;
+
+/// Whether to map files, as given with `--mmap` and `--no-mmap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MmapChoice {
+    /// Map regular files of at least [`THRESHOLD`] bytes.
+    #[default]
+    Auto,
+    /// Map every regular file that isn't empty.
+    Always,
+    Never,
+}
+
+/// A read-only mapping of a whole file.
+#[derive(Debug)]
+pub struct Mmap {
+    ptr: *const u8,
+    len: usize,
+}
+
+// the mapping is read-only and owned by this value alone
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Maps `file` if `choice` says so and it can be mapped. `None` means
+    /// it should be read instead.
+    pub fn for_file(file: &File, choice: MmapChoice) -> Option<Mmap> {
+        let metadata = file.metadata().ok()?;
+        let wanted = match choice {
+            MmapChoice::Auto => metadata.len() >= THRESHOLD,
+            MmapChoice::Always => metadata.len() > 0,
+            MmapChoice::Never => false,
+        };
+        if !wanted || !metadata.is_file() {
+            return None;
+        }
+        // a failed mapping isn't an error, the file can still be read
+        Mmap::map(file, usize::try_from(metadata.len()).ok()?).ok()
+    }
+
+    #[cfg(unix)]
+    fn map(file: &File, len: usize) -> io::Result<Mmap> {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: a fresh private read-only mapping of an open file, which
+        // is unmapped again on drop and never handed out mutably
+        let ptr = unsafe { sys::mmap(std::ptr::null_mut(), len, sys::PROT_READ, sys::MAP_PRIVATE, file.as_raw_fd(), 0) };
+        if ptr == sys::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr: ptr as *const u8, len })
+    }
+
+    #[cfg(not(unix))]
+    fn map(_file: &File, _len: usize) -> io::Result<Mmap> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: ptr points to len readable bytes for as long as self lives
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        // SAFETY: ptr and len are exactly what mmap returned
+        unsafe {
+            sys::munmap(self.ptr as *mut _, self.len);
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::{c_int, c_long, c_void};
+
+    // the same on Linux, the BSDs and macOS
+    pub const PROT_READ: c_int = 1;
+    pub const MAP_PRIVATE: c_int = 2;
+    pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        // off_t is a long on every Unix Rust supports without large file
+        // offsets, and the offset used here is always 0 anyway
+        pub fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: c_long) -> *mut c_void;
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn maps_regular_files() {
+        let path = std::env::temp_dir().join(format!("kkjgrep-mmap-{}", std::process::id()));
+        fs::File::create(&path).unwrap().write_all(b"rust\ngo\n").unwrap();
+        let file = File::open(&path).unwrap();
+
+        let map = Mmap::for_file(&file, MmapChoice::Always);
+        if cfg!(unix) {
+            assert_eq!(Some(&b"rust\ngo\n"[..]), map.as_deref());
+        } else {
+            assert!(map.is_none());
+        }
+        assert!(Mmap::for_file(&file, MmapChoice::Auto).is_none());
+        assert!(Mmap::for_file(&file, MmapChoice::Never).is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+//! A pool of worker threads for searching many files at once.
+//!
+//! Jobs are handed out by a producer thread over a bounded channel, so a
+//! directory walk never runs far ahead of the searches. Each result is
+//! handed back to the calling thread, in job order if asked to. Output
+//! written through [`Ordered`] keeps the output of one job together and in
+//! job order no matter how the work was interleaved.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex, MutexGuard};
+use std::thread;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Pool {
+    /// Number of worker threads, at least one.
+    pub threads: usize,
+    /// Emit results in the order of the jobs rather than as they finish.
+    pub sorted: bool,
+}
+
+impl Pool {
+    /// Runs `work` on every job on the worker threads and passes each result
+    /// to `emit` on the calling thread. `work` also gets the index of the
+    /// job, counting from 0 in the order of `jobs`. Once `emit` breaks, jobs
+    /// that haven't started are dropped and no more results are emitted.
+    pub fn run<I, J, R, W, E>(&self, jobs: I, work: W, mut emit: E)
+    where
+        I: Iterator<Item = J> + Send,
+        J: Send,
+        R: Send,
+        W: Fn(usize, J) -> R + Sync,
+        E: FnMut(R) -> ControlFlow<()>,
+    {
+        let threads = self.threads.max(1);
+        let (job_tx, job_rx) = mpsc::sync_channel::<(usize, J)>(threads * 4);
+        let (done_tx, done_rx) = mpsc::channel::<(usize, R)>();
+        let job_rx = Mutex::new(job_rx);
+        let stop = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                let (job_rx, work, stop, done_tx) = (&job_rx, &work, &stop, done_tx.clone());
+                scope.spawn(move || loop {
+                    // the lock is only held while waiting for the next job
+                    let next = job_rx.lock().unwrap_or_else(|err| err.into_inner()).recv();
+                    let Ok((index, job)) = next else { break };
+                    // keep taking jobs after a stop so the producer can't
+                    // block on a full channel
+                    if !stop.load(Ordering::Relaxed) {
+                        let _ = done_tx.send((index, work(index, job)));
+                    }
+                });
+            }
+            drop(done_tx);
+
+            let producer_stop = &stop;
+            scope.spawn(move || {
+                for job in jobs.enumerate() {
+                    if producer_stop.load(Ordering::Relaxed) || job_tx.send(job).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            // results that finished before the ones that come before them
+            let mut waiting = BTreeMap::new();
+            let mut next = 0;
+            'results: for (index, result) in done_rx.iter() {
+                if !self.sorted {
+                    if emit(result).is_break() {
+                        break;
+                    }
+                    continue;
+                }
+                waiting.insert(index, result);
+                while let Some(result) = waiting.remove(&next) {
+                    next += 1;
+                    if emit(result).is_break() {
+                        break 'results;
+                    }
+                }
+            }
+            stop.store(true, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Output shared by jobs that has to come out in job order. The job at the
+/// head of the order writes straight through to the output; the ones after
+/// it are buffered until everything before them has been written.
+#[derive(Debug)]
+pub struct Ordered<W> {
+    state: Mutex<State<W>>,
+    // index of the job at the head of the order, only changed with the
+    // state locked
+    next: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct State<W> {
+    out: W,
+    // output of jobs that finished before their turn came
+    finished: BTreeMap<usize, Vec<u8>>,
+}
+
+impl<W: Write> Ordered<W> {
+    pub fn new(out: W) -> Ordered<W> {
+        Ordered { state: Mutex::new(State { out, finished: BTreeMap::new() }), next: AtomicUsize::new(0) }
+    }
+
+    /// A writer for the job with the given index. Every job has to call
+    /// [`JobWriter::finish`] on its writer, or the jobs after it are never
+    /// written.
+    pub fn writer(&self, index: usize) -> JobWriter<'_, W> {
+        JobWriter { ordered: self, index, buf: Vec::new() }
+    }
+
+    /// Calls `f` with the output locked.
+    pub fn with_out<T>(&self, f: impl FnOnce(&mut W) -> T) -> T {
+        f(&mut self.state().out)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.state.into_inner().unwrap_or_else(|err| err.into_inner()).out
+    }
+
+    fn state(&self) -> MutexGuard<'_, State<W>> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Writes the output of one job through [`Ordered`].
+#[derive(Debug)]
+pub struct JobWriter<'a, W: Write> {
+    ordered: &'a Ordered<W>,
+    index: usize,
+    // what was written before the job's turn came
+    buf: Vec<u8>,
+}
+
+impl<W: Write> JobWriter<'_, W> {
+    fn is_head(&self) -> bool {
+        self.ordered.next.load(Ordering::Acquire) == self.index
+    }
+
+    /// Writes whatever is still buffered once it's the job's turn, and the
+    /// output of the jobs after it that have already finished.
+    pub fn finish(mut self) -> io::Result<()> {
+        let mut state = self.ordered.state();
+        if !self.is_head() {
+            state.finished.insert(self.index, std::mem::take(&mut self.buf));
+            return Ok(());
+        }
+
+        // the order moves on even when writing fails, so nothing waits on
+        // a job that will never be written
+        let mut result = state.out.write_all(&self.buf);
+        let mut next = self.index + 1;
+        while let Some(buf) = state.finished.remove(&next) {
+            result = result.and_then(|()| state.out.write_all(&buf));
+            next += 1;
+        }
+        self.ordered.next.store(next, Ordering::Release);
+        result
+    }
+}
+
+impl<W: Write> Write for JobWriter<'_, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if !self.is_head() {
+            self.buf.extend_from_slice(data);
+            return Ok(data.len());
+        }
+        let mut state = self.ordered.state();
+        if !self.buf.is_empty() {
+            state.out.write_all(&std::mem::take(&mut self.buf))?;
+        }
+        state.out.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.is_head() {
+            return Ok(());
+        }
+        let mut state = self.ordered.state();
+        state.out.write_all(&std::mem::take(&mut self.buf))?;
+        state.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn sorted_results_follow_job_order() {
+        let pool = Pool { threads: 4, sorted: true };
+        let mut results = Vec::new();
+
+        pool.run(
+            0..20u64,
+            |_, job| {
+                // later jobs finish first
+                thread::sleep(Duration::from_millis(20 - job));
+                job * 2
+            },
+            |result| {
+                results.push(result);
+                ControlFlow::Continue(())
+            },
+        );
+
+        assert_eq!((0..20).map(|job| job * 2).collect::<Vec<_>>(), results);
+    }
+
+    #[test]
+    fn unsorted_results_are_all_emitted() {
+        let pool = Pool { threads: 3, sorted: false };
+        let mut results = Vec::new();
+
+        pool.run(0..100, |_, job| job + 1, |result| {
+            results.push(result);
+            ControlFlow::Continue(())
+        });
+        results.sort();
+
+        assert_eq!((1..=100).collect::<Vec<_>>(), results);
+    }
+
+    #[test]
+    fn stops_when_emit_breaks() {
+        let pool = Pool { threads: 2, sorted: true };
+        let mut results = Vec::new();
+
+        pool.run(0.., |_, job: u64| job, |result| {
+            results.push(result);
+            if result == 9 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        });
+
+        assert_eq!((0..10).collect::<Vec<_>>(), results);
+    }
+
+    #[test]
+    fn ordered_output_streams_the_head() {
+        let ordered = Ordered::new(Vec::new());
+        let (mut first, mut second, mut third) = (ordered.writer(0), ordered.writer(1), ordered.writer(2));
+
+        write!(third, "3").unwrap();
+        write!(first, "1").unwrap();
+        // the head isn't held back
+        assert_eq!(b"1".to_vec(), ordered.with_out(|out| out.clone()));
+
+        write!(second, "2").unwrap();
+        third.finish().unwrap();
+        first.finish().unwrap();
+        write!(second, "2").unwrap();
+        assert_eq!(b"122".to_vec(), ordered.with_out(|out| out.clone()));
+
+        second.finish().unwrap();
+        assert_eq!(b"1223".to_vec(), ordered.into_inner());
+    }
+}
@@ -0,0 +1,961 @@
+//! A small regular expression engine.
+//!
+//! Patterns are parsed into an AST, compiled into a list of NFA instructions
+//! and executed with a Pike VM, so matching time stays linear in the length
+//! of the line no matter how the pattern is written.
+//!
+//! Supported syntax: literals, `.`, `[...]` classes (ranges, negation,
+//! `[:alpha:]`-style names), `\d \w \s` and their negations, the anchors
+//! `^ $ \b \B`, groups `(...)` / `(?:...)`, alternation `|` and the
+//! repetitions `* + ? {n} {n,} {n,m}` with lazy `?` variants.
+
+use std::cell::RefCell;
+use std::fmt;
+
+use crate::casefold;
+
+const MAX_REPEAT: u32 = 1000;
+const MAX_PROGRAM: usize = 100_000;
+// how deeply groups and repetitions can nest, so that neither parsing nor
+// compiling a pattern runs out of stack
+const MAX_NESTING: usize = 250;
+
+#[derive(Debug, Clone)]
+pub struct Regex {
+    prog: Vec<Inst>,
+    ignore_case: bool,
+}
+
+thread_local! {
+    // thread lists are reused from one search to the next, so matching a
+    // line doesn't allocate
+    static CACHE: RefCell<Cache> = RefCell::new(Cache::default());
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    /// Character position in the pattern where the problem was found.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// What a match has to line up with, as selected by `-w` and `-x`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Bounds {
+    #[default]
+    Any,
+    /// No word character right before or after the match.
+    Word,
+    /// The match spans the whole line.
+    Line,
+}
+
+impl Bounds {
+    /// Whether the match at `start..end` in `haystack` lines up as required.
+    pub fn holds(self, haystack: &[u8], start: usize, end: usize) -> bool {
+        let (before, after) = self.looks();
+        before.is_none_or(|look| look.holds(haystack, start)) && after.is_none_or(|look| look.holds(haystack, end))
+    }
+
+    fn looks(self) -> (Option<Look>, Option<Look>) {
+        match self {
+            Bounds::Any => (None, None),
+            Bounds::Word => (Some(Look::NoWordBefore), Some(Look::NoWordAfter)),
+            Bounds::Line => (Some(Look::Start), Some(Look::End)),
+        }
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, Error> {
+        Self::build(pattern, false, Bounds::Any)
+    }
+
+    /// Compiles `pattern` so that it only matches within `bounds`.
+    pub fn with_bounds(pattern: &str, ignore_case: bool, bounds: Bounds) -> Result<Regex, Error> {
+        Self::build(pattern, ignore_case, bounds)
+    }
+
+    fn build(pattern: &str, ignore_case: bool, bounds: Bounds) -> Result<Regex, Error> {
+        let ast = Parser::new(pattern).parse()?;
+        let mut compiler = Compiler { prog: Vec::new(), ignore_case };
+        // the bounds go around the pattern's instructions, so they don't nest
+        // it any deeper
+        let (before, after) = bounds.looks();
+        compiler.prog.extend(before.map(Inst::Look));
+        compiler.compile(&ast, 0).map_err(|message| Error { position: 0, message })?;
+        compiler.prog.extend(after.map(Inst::Look));
+        compiler.prog.push(Inst::Match);
+
+        Ok(Regex { prog: compiler.prog, ignore_case })
+    }
+
+    /// Returns the byte range of the leftmost-first match starting the
+    /// search at `start`. Anchors still see the whole haystack.
+    ///
+    /// The haystack is decoded as UTF-8; bytes that aren't part of a valid
+    /// sequence never match anything but are skipped over.
+    pub fn find_at(&self, haystack: &[u8], start: usize) -> Option<(usize, usize)> {
+        CACHE.with(|cache| {
+            let mut cache = cache.borrow_mut();
+            cache.reset(self.prog.len());
+            self.run(&mut cache, haystack, start)
+        })
+    }
+
+    fn run(&self, cache: &mut Cache, haystack: &[u8], start: usize) -> Option<(usize, usize)> {
+        let Cache { clist, nlist, stack } = cache;
+        let mut matched = None;
+        let mut pos = start;
+
+        loop {
+            if matched.is_none() {
+                self.add_thread(clist, stack, Thread { pc: 0, start: pos, folded: 0 }, pos, haystack);
+            }
+            if clist.is_empty() && matched.is_some() {
+                break;
+            }
+
+            let (c, len) = decode(haystack, pos).unwrap_or((None, 0));
+            let next = pos + len;
+            // a char that folds to several, like ß to ss, can also be matched
+            // by Fold instructions one folded char at a time
+            let fold = c.filter(|_| self.ignore_case).map(casefold::fold);
+            let folded = fold.as_ref().map_or(&[][..], |fold| fold.as_slice());
+            let folded = if folded.len() > 1 { folded } else { &[] };
+
+            // threads part way through a char are added to clist as it's
+            // walked, so it's walked by index
+            let mut i = 0;
+            while i < clist.list.len() {
+                let thread = clist.list[i];
+                i += 1;
+                let whole = thread.folded == 0;
+                let step = match &self.prog[thread.pc] {
+                    Inst::Match if whole => {
+                        matched = Some((thread.start, pos));
+                        // every remaining thread has a lower priority
+                        break;
+                    }
+                    Inst::Fold(x) if whole && c.is_some_and(|c| casefold::simple(c) == *x) => true,
+                    Inst::Fold(x) => {
+                        let k = thread.folded as usize;
+                        if folded.get(k).is_some_and(|&f| casefold::simple(f) == *x) {
+                            let rest = Thread { pc: thread.pc + 1, folded: thread.folded + 1, ..thread };
+                            if k + 1 == folded.len() {
+                                self.add_thread(nlist, stack, Thread { folded: 0, ..rest }, next, haystack);
+                            } else {
+                                self.add_thread(clist, stack, rest, pos, haystack);
+                            }
+                        }
+                        false
+                    }
+                    Inst::Char(x) => whole && c == Some(*x),
+                    Inst::Any => whole && c.is_some_and(|c| c != '\n'),
+                    Inst::Class(class) => whole && c.is_some_and(|c| class.matches(c)),
+                    Inst::Match | Inst::Look(_) | Inst::Split(..) | Inst::Jmp(_) => false,
+                };
+                if step {
+                    self.add_thread(nlist, stack, Thread { pc: thread.pc + 1, ..thread }, next, haystack);
+                }
+            }
+
+            if len == 0 {
+                break;
+            }
+            pos = next;
+            std::mem::swap(clist, nlist);
+            nlist.clear();
+        }
+
+        matched
+    }
+
+    // follows jumps, splits and assertions from `thread` and adds every
+    // thread that ends up at an instruction consuming input
+    fn add_thread(&self, threads: &mut Threads, stack: &mut Vec<usize>, thread: Thread, pos: usize, haystack: &[u8]) {
+        stack.clear();
+        stack.push(thread.pc);
+
+        while let Some(pc) = stack.pop() {
+            if !threads.insert(pc, thread.folded) {
+                continue;
+            }
+            match &self.prog[pc] {
+                Inst::Jmp(to) => stack.push(*to),
+                Inst::Split(first, second) => {
+                    stack.push(*second);
+                    stack.push(*first);
+                }
+                Inst::Look(look) => {
+                    // there's no position to look at in the middle of a char
+                    if thread.folded == 0 && look.holds(haystack, pos) {
+                        stack.push(pc + 1);
+                    }
+                }
+                _ => threads.list.push(Thread { pc, ..thread }),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Thread {
+    pc: usize,
+    // where the match started
+    start: usize,
+    // how many chars of the current char's folding have been matched
+    folded: u8,
+}
+
+#[derive(Debug, Default)]
+struct Threads {
+    list: Vec<Thread>,
+    // for each instruction, a bit for every number of folded chars matched
+    seen: Vec<u8>,
+}
+
+impl Threads {
+    fn insert(&mut self, pc: usize, folded: u8) -> bool {
+        let bit = 1 << folded;
+        let new = self.seen[pc] & bit == 0;
+        self.seen[pc] |= bit;
+        new
+    }
+
+    fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.list.clear();
+        self.seen.iter_mut().for_each(|seen| *seen = 0);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    clist: Threads,
+    nlist: Threads,
+    stack: Vec<usize>,
+}
+
+impl Cache {
+    fn reset(&mut self, len: usize) {
+        for threads in [&mut self.clist, &mut self.nlist] {
+            threads.seen.resize(len, 0);
+            threads.clear();
+        }
+    }
+}
+
+/// Escapes every character the parser would treat as syntax, so the result
+/// matches `literal` exactly.
+pub fn escape(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Decodes the char starting at `pos`, returning it with its length in
+/// bytes. An invalid sequence decodes as `None` with a length of one.
+pub(crate) fn decode(bytes: &[u8], pos: usize) -> Option<(Option<char>, usize)> {
+    let first = *bytes.get(pos)?;
+    let len = match first {
+        0x00..=0x7f => return Some((Some(first as char), 1)),
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => return Some((None, 1)),
+    };
+
+    match bytes.get(pos..pos + len).and_then(|seq| std::str::from_utf8(seq).ok()) {
+        Some(seq) => Some((seq.chars().next(), len)),
+        None => Some((None, 1)),
+    }
+}
+
+/// Decodes the char that ends right before `pos`, if it is valid.
+pub(crate) fn decode_last(bytes: &[u8], pos: usize) -> Option<char> {
+    (1..=4.min(pos)).find_map(|len| match decode(bytes, pos - len) {
+        Some((c, n)) if n == len => c,
+        _ => None,
+    })
+}
+
+pub(crate) fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn single(mut chars: impl Iterator<Item = char>) -> Option<char> {
+    let c = chars.next()?;
+    chars.next().is_none().then_some(c)
+}
+
+#[derive(Debug, Clone)]
+enum Inst {
+    Char(char),
+    Fold(char),
+    Any,
+    Class(Class),
+    Look(Look),
+    Split(usize, usize),
+    Jmp(usize),
+    Match,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Look {
+    Start,
+    End,
+    WordBoundary,
+    NotWordBoundary,
+    // only used for -w, which also accepts non-word characters at the edges
+    NoWordBefore,
+    NoWordAfter,
+}
+
+impl Look {
+    fn holds(self, haystack: &[u8], pos: usize) -> bool {
+        let before = || decode_last(haystack, pos).is_some_and(is_word_char);
+        let after = || decode(haystack, pos).and_then(|(c, _)| c).is_some_and(is_word_char);
+
+        match self {
+            Look::Start => pos == 0,
+            Look::End => pos == haystack.len(),
+            Look::WordBoundary => before() != after(),
+            Look::NotWordBoundary => before() == after(),
+            Look::NoWordBefore => !before(),
+            Look::NoWordAfter => !after(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Perl {
+    Digit,
+    Word,
+    Space,
+}
+
+impl Perl {
+    fn matches(self, c: char) -> bool {
+        match self {
+            Perl::Digit => c.is_ascii_digit(),
+            Perl::Word => is_word_char(c),
+            Perl::Space => c.is_whitespace(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ClassItem {
+    Range(char, char),
+    Perl(Perl, bool),
+    Posix(fn(&char) -> bool),
+}
+
+#[derive(Debug, Clone)]
+struct Class {
+    items: Vec<ClassItem>,
+    negated: bool,
+    ignore_case: bool,
+}
+
+impl Class {
+    fn matches(&self, c: char) -> bool {
+        let hit = if self.ignore_case {
+            self.contains(c)
+                || single(c.to_lowercase()).is_some_and(|c| self.contains(c))
+                || single(c.to_uppercase()).is_some_and(|c| self.contains(c))
+        } else {
+            self.contains(c)
+        };
+        hit != self.negated
+    }
+
+    fn contains(&self, c: char) -> bool {
+        self.items.iter().any(|item| match *item {
+            ClassItem::Range(lo, hi) => lo <= c && c <= hi,
+            ClassItem::Perl(perl, negated) => perl.matches(c) != negated,
+            ClassItem::Posix(f) => f(&c),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    Class(Class),
+    Look(Look),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat { node: Box<Node>, min: u32, max: Option<u32>, greedy: bool },
+}
+
+// the parse_* methods return a node along with its height, how many
+// concatenations, alternations and repetitions are nested in it
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    // open groups
+    depth: usize,
+}
+
+impl Parser {
+    fn new(pattern: &str) -> Parser {
+        Parser { chars: pattern.chars().collect(), pos: 0, depth: 0 }
+    }
+
+    fn parse(mut self) -> Result<Node, Error> {
+        let (node, _) = self.parse_alternate()?;
+        match self.peek() {
+            None => Ok(node),
+            Some(_) => Err(self.error("unmatched closing parenthesis")),
+        }
+    }
+
+    fn error(&self, message: &str) -> Error {
+        Error { position: self.pos, message: message.to_string() }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn next_char(&mut self, message: &str) -> Result<char, Error> {
+        let c = self.peek().ok_or_else(|| self.error(message))?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    // height of a node that contains one of the given height
+    fn nest(&self, height: usize) -> Result<usize, Error> {
+        match height {
+            MAX_NESTING.. => Err(self.error("pattern nested too deeply")),
+            _ => Ok(height + 1),
+        }
+    }
+
+    fn parse_alternate(&mut self) -> Result<(Node, usize), Error> {
+        let (node, mut height) = self.parse_concat()?;
+        let mut branches = vec![node];
+        while self.eat('|') {
+            let (node, branch_height) = self.parse_concat()?;
+            branches.push(node);
+            height = height.max(branch_height);
+        }
+
+        Ok(match branches.len() {
+            1 => (branches.pop().unwrap(), height),
+            _ => (Node::Alternate(branches), self.nest(height)?),
+        })
+    }
+
+    fn parse_concat(&mut self) -> Result<(Node, usize), Error> {
+        let mut nodes = Vec::new();
+        let mut height = 0;
+
+        while let Some(c) = self.peek() {
+            match c {
+                '|' | ')' => break,
+                '*' | '+' | '?' => return Err(self.error("repetition operator missing expression")),
+                _ => {
+                    let (atom, atom_height) = self.parse_atom()?;
+                    let (node, node_height) = self.parse_repeat(atom, atom_height)?;
+                    nodes.push(node);
+                    height = height.max(node_height);
+                }
+            }
+        }
+
+        Ok(match nodes.len() {
+            0 => (Node::Empty, 0),
+            1 => (nodes.pop().unwrap(), height),
+            _ => (Node::Concat(nodes), self.nest(height)?),
+        })
+    }
+
+    fn parse_repeat(&mut self, mut node: Node, mut height: usize) -> Result<(Node, usize), Error> {
+        loop {
+            let start = self.pos;
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => match self.parse_counts()? {
+                    Some(counts) => counts,
+                    None => return Ok((node, height)),
+                },
+                _ => return Ok((node, height)),
+            };
+            if start == self.pos {
+                self.pos += 1;
+            }
+            if let Node::Look(_) | Node::Empty = node {
+                self.pos = start;
+                return Err(self.error("repetition operator missing expression"));
+            }
+            let greedy = !self.eat('?');
+            height = self.nest(height)?;
+            node = Node::Repeat { node: Box::new(node), min, max, greedy };
+        }
+    }
+
+    // `{` that doesn't start a valid counted repetition is a literal brace
+    fn parse_counts(&mut self) -> Result<Option<(u32, Option<u32>)>, Error> {
+        let start = self.pos;
+        self.pos += 1;
+
+        let min = self.parse_number()?;
+        let max = if self.eat(',') {
+            if self.peek() == Some('}') { None } else { self.parse_number()? }
+        } else {
+            min
+        };
+
+        match (min, self.eat('}')) {
+            (Some(min), true) => {
+                if max.is_some_and(|max| max < min) {
+                    self.pos = start;
+                    return Err(self.error("invalid repetition range"));
+                }
+                Ok(Some((min, max)))
+            }
+            _ => {
+                self.pos = start;
+                Ok(None)
+            }
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Option<u32>, Error> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Ok(None);
+        }
+
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        match digits.parse::<u32>() {
+            Ok(n) if n <= MAX_REPEAT => Ok(Some(n)),
+            _ => {
+                self.pos = start;
+                Err(self.error("repetition count too large"))
+            }
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<(Node, usize), Error> {
+        let c = self.next_char("unexpected end of pattern")?;
+
+        let leaf = match c {
+            '(' => return self.parse_group(),
+            '[' => self.parse_class(),
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Look(Look::Start)),
+            '$' => Ok(Node::Look(Look::End)),
+            '\\' => match self.parse_escape()? {
+                Escape::Char(c) => Ok(Node::Char(c)),
+                Escape::Perl(perl, negated) => Ok(Node::Class(Class {
+                    items: vec![ClassItem::Perl(perl, negated)],
+                    negated: false,
+                    ignore_case: false,
+                })),
+                Escape::Look(look) => Ok(Node::Look(look)),
+            },
+            c => Ok(Node::Char(c)),
+        };
+        leaf.map(|node| (node, 0))
+    }
+
+    fn parse_group(&mut self) -> Result<(Node, usize), Error> {
+        if self.eat('?') && !self.eat(':') {
+            return Err(self.error("unsupported group flag"));
+        }
+        if self.depth == MAX_NESTING {
+            return Err(self.error("pattern nested too deeply"));
+        }
+        self.depth += 1;
+        let group = self.parse_alternate()?;
+        self.depth -= 1;
+        if !self.eat(')') {
+            return Err(self.error("unclosed group"));
+        }
+        Ok(group)
+    }
+
+    fn parse_escape(&mut self) -> Result<Escape, Error> {
+        let c = self.next_char("trailing backslash")?;
+
+        Ok(match c {
+            'd' => Escape::Perl(Perl::Digit, false),
+            'D' => Escape::Perl(Perl::Digit, true),
+            'w' => Escape::Perl(Perl::Word, false),
+            'W' => Escape::Perl(Perl::Word, true),
+            's' => Escape::Perl(Perl::Space, false),
+            'S' => Escape::Perl(Perl::Space, true),
+            'b' => Escape::Look(Look::WordBoundary),
+            'B' => Escape::Look(Look::NotWordBoundary),
+            'n' => Escape::Char('\n'),
+            'r' => Escape::Char('\r'),
+            't' => Escape::Char('\t'),
+            c if c.is_alphanumeric() => {
+                self.pos -= 1;
+                return Err(self.error("unrecognized escape sequence"));
+            }
+            c => Escape::Char(c),
+        })
+    }
+
+    fn parse_class(&mut self) -> Result<Node, Error> {
+        let start = self.pos - 1;
+        let negated = self.eat('^');
+        let mut items = Vec::new();
+        let mut first = true;
+
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => {
+                    self.pos = start;
+                    return Err(self.error("unclosed character class"));
+                }
+            };
+            self.pos += 1;
+
+            let lo = match c {
+                ']' if !first => break,
+                '[' if self.peek() == Some(':') => {
+                    items.push(self.parse_posix()?);
+                    first = false;
+                    continue;
+                }
+                '\\' => match self.parse_escape()? {
+                    Escape::Char(c) => c,
+                    Escape::Perl(perl, negated) => {
+                        items.push(ClassItem::Perl(perl, negated));
+                        first = false;
+                        continue;
+                    }
+                    Escape::Look(_) => {
+                        self.pos -= 1;
+                        return Err(self.error("assertion not allowed in character class"));
+                    }
+                },
+                c => c,
+            };
+            first = false;
+
+            let is_range = self.peek() == Some('-')
+                && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']');
+            if !is_range {
+                items.push(ClassItem::Range(lo, lo));
+                continue;
+            }
+            self.pos += 1;
+
+            let hi = match self.next_char("unclosed character class")? {
+                '\\' => match self.parse_escape()? {
+                    Escape::Char(c) => c,
+                    _ => return Err(self.error("invalid range end")),
+                },
+                c => c,
+            };
+            if hi < lo {
+                return Err(self.error("invalid character class range"));
+            }
+            items.push(ClassItem::Range(lo, hi));
+        }
+
+        Ok(Node::Class(Class { items, negated, ignore_case: false }))
+    }
+
+    fn parse_posix(&mut self) -> Result<ClassItem, Error> {
+        let start = self.pos - 1;
+        self.pos += 1;
+        let name_start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_lowercase()) {
+            self.pos += 1;
+        }
+        let name: String = self.chars[name_start..self.pos].iter().collect();
+
+        if !(self.eat(':') && self.eat(']')) {
+            self.pos = start;
+            return Err(self.error("invalid character class name"));
+        }
+
+        let f: fn(&char) -> bool = match name.as_str() {
+            "alnum" => char::is_ascii_alphanumeric,
+            "alpha" => char::is_ascii_alphabetic,
+            "blank" => |c| *c == ' ' || *c == '\t',
+            "cntrl" => char::is_ascii_control,
+            "digit" => char::is_ascii_digit,
+            "graph" => char::is_ascii_graphic,
+            "lower" => char::is_ascii_lowercase,
+            "print" => |c| c.is_ascii_graphic() || *c == ' ',
+            "punct" => char::is_ascii_punctuation,
+            "space" => |c| c.is_ascii_whitespace() || *c == '\x0b',
+            "upper" => char::is_ascii_uppercase,
+            "word" => |c| c.is_ascii_alphanumeric() || *c == '_',
+            "xdigit" => char::is_ascii_hexdigit,
+            _ => {
+                self.pos = start;
+                return Err(self.error("invalid character class name"));
+            }
+        };
+
+        Ok(ClassItem::Posix(f))
+    }
+}
+
+enum Escape {
+    Char(char),
+    Perl(Perl, bool),
+    Look(Look),
+}
+
+struct Compiler {
+    prog: Vec<Inst>,
+    ignore_case: bool,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> Result<usize, String> {
+        if self.prog.len() >= MAX_PROGRAM {
+            return Err("pattern too large".to_string());
+        }
+        self.prog.push(inst);
+        Ok(self.prog.len() - 1)
+    }
+
+    fn compile(&mut self, node: &Node, depth: usize) -> Result<(), String> {
+        if depth > MAX_NESTING {
+            return Err("pattern nested too deeply".to_string());
+        }
+        match node {
+            Node::Empty => {}
+            Node::Char(c) if self.ignore_case && casefold::fold(*c).nth(1).is_some() => {
+                // chars like ß also match their full folding, spelled out: ss
+                let split = self.push(Inst::Split(0, 0))?;
+                self.push(Inst::Fold(casefold::simple(*c)))?;
+                let jump = self.push(Inst::Jmp(0))?;
+                self.prog[split] = Inst::Split(split + 1, jump + 1);
+                for folded in casefold::fold(*c) {
+                    self.push(Inst::Fold(casefold::simple(folded)))?;
+                }
+                self.prog[jump] = Inst::Jmp(self.prog.len());
+            }
+            Node::Char(c) => {
+                let inst = if self.ignore_case { Inst::Fold(casefold::simple(*c)) } else { Inst::Char(*c) };
+                self.push(inst)?;
+            }
+            Node::Any => {
+                self.push(Inst::Any)?;
+            }
+            Node::Class(class) => {
+                let class = Class { ignore_case: self.ignore_case, ..class.clone() };
+                self.push(Inst::Class(class))?;
+            }
+            Node::Look(look) => {
+                self.push(Inst::Look(*look))?;
+            }
+            Node::Concat(nodes) => {
+                for node in nodes {
+                    self.compile(node, depth + 1)?;
+                }
+            }
+            Node::Alternate(branches) => {
+                let mut jumps = Vec::new();
+                for (i, branch) in branches.iter().enumerate() {
+                    if i + 1 < branches.len() {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.compile(branch, depth + 1)?;
+                        jumps.push(self.push(Inst::Jmp(0))?);
+                        let next = self.prog.len();
+                        self.prog[split] = Inst::Split(split + 1, next);
+                    } else {
+                        self.compile(branch, depth + 1)?;
+                    }
+                }
+                let end = self.prog.len();
+                for jump in jumps {
+                    self.prog[jump] = Inst::Jmp(end);
+                }
+            }
+            Node::Repeat { node, min, max, greedy } => {
+                for _ in 0..*min {
+                    self.compile(node, depth + 1)?;
+                }
+                match max {
+                    None => {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.compile(node, depth + 1)?;
+                        self.push(Inst::Jmp(split))?;
+                        let end = self.prog.len();
+                        self.prog[split] = self.split(split + 1, end, *greedy);
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.push(Inst::Split(0, 0))?);
+                            self.compile(node, depth + 1)?;
+                        }
+                        let end = self.prog.len();
+                        for split in splits {
+                            self.prog[split] = self.split(split + 1, end, *greedy);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn split(&self, body: usize, end: usize, greedy: bool) -> Inst {
+        if greedy { Inst::Split(body, end) } else { Inst::Split(end, body) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find_bytes(pattern: &str, haystack: &[u8]) -> Option<(usize, usize)> {
+        Regex::new(pattern).unwrap().find_at(haystack, 0)
+    }
+
+    fn find(pattern: &str, haystack: &str) -> Option<(usize, usize)> {
+        Regex::new(pattern).unwrap().find_at(haystack.as_bytes(), 0)
+    }
+
+    #[test]
+    fn literals_and_classes() {
+        assert_eq!(Some((1, 4)), find("ust", "Rust"));
+        assert_eq!(Some((4, 7)), find("[0-9]+", "abc 123"));
+        assert_eq!(Some((0, 3)), find(r"\w+", "foo bar"));
+        assert_eq!(Some((3, 4)), find("[^a-z]", "abc!"));
+        assert_eq!(Some((0, 2)), find("[[:upper:]]{2}", "OKay"));
+        assert_eq!(None, find("x", "Rust"));
+    }
+
+    #[test]
+    fn anchors_and_boundaries() {
+        assert_eq!(Some((0, 4)), find("^Rust", "Rust: rust"));
+        assert_eq!(None, find("^rust", "Rust: rust"));
+        assert_eq!(Some((6, 10)), find("rust$", "Rust: rust"));
+        assert_eq!(Some((4, 6)), find(r"\bid\b", "an, id"));
+        assert_eq!(None, find(r"\bid\b", "valid identity"));
+    }
+
+    #[test]
+    fn bounds() {
+        let find = |pattern, bounds, haystack: &str| {
+            Regex::with_bounds(pattern, false, bounds).unwrap().find_at(haystack.as_bytes(), 0)
+        };
+
+        assert_eq!(Some((13, 15)), find("id", Bounds::Word, "valid, 한id id"));
+        assert_eq!(Some((2, 5)), find("-x|-xy", Bounds::Word, "a -xy b"));
+        assert_eq!(None, find("id", Bounds::Line, "id "));
+        assert_eq!(Some((0, 3)), find("a|ab|abc", Bounds::Line, "abc"));
+        assert!(Bounds::Word.holds(b"(id)", 1, 3));
+        assert!(!Bounds::Line.holds(b"(id)", 1, 3));
+    }
+
+    #[test]
+    fn alternation_and_repetition() {
+        assert_eq!(Some((0, 3)), find("cat|dog", "cat dog"));
+        assert_eq!(Some((4, 7)), find("(?:dog|cow)", "cat dog"));
+        assert_eq!(Some((0, 4)), find("a{2,4}", "aaaaa"));
+        assert_eq!(Some((0, 2)), find("a{2,4}?", "aaaaa"));
+        assert_eq!(Some((0, 6)), find("<.*>", "<a><b>"));
+        assert_eq!(Some((0, 3)), find("<.*?>", "<a><b>"));
+        assert_eq!(Some((0, 0)), find("x*", "abc"));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let find = |pattern, haystack: &str| {
+            Regex::with_bounds(pattern, true, Bounds::Any).unwrap().find_at(haystack.as_bytes(), 0)
+        };
+        assert_eq!(Some((1, 6)), find("rUsT[a-c]", "TRUSTB"));
+        assert_eq!(Some((0, 4)), find("σς", "ΣΣ"));
+        assert_eq!(Some((0, 7)), find("straße", "STRASSE"));
+        assert_eq!(Some((0, 8)), find("stra(ß)e", "STRAẞE"));
+        assert_eq!(None, find("i", "ı"));
+
+        // chars in the haystack that fold to several chars
+        assert_eq!(Some((0, 7)), find("strasse", "straße"));
+        assert_eq!(Some((0, 7)), find("stras+e", "straße"));
+        assert_eq!(Some((1, 8)), find("stra(ss|x)e", "-Straße"));
+        assert_eq!(Some((1, 5)), find("s[ß]", "xSẞ"));
+        assert_eq!(None, find("stras.e", "straße"));
+        assert_eq!(None, find("stras", "straße"));
+        assert_eq!(None, find("s$", "ß"));
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        assert_eq!(Some((5, 8)), find_bytes("b.d", b"\xffab\xe9dbcd"));
+        assert_eq!(Some((1, 5)), find_bytes("a.c", "xa\u{e9}c".as_bytes()));
+        assert_eq!(Some((1, 3)), find_bytes(r"\bé", b"\xff\xc3\xa9"));
+    }
+
+    #[test]
+    fn escaped_literals() {
+        let literal = r"a.b*c(d)[e]{2}|^$\";
+        let re = Regex::new(&escape(literal)).unwrap();
+
+        assert_eq!(Some((1, 1 + literal.len())), re.find_at(format!("x{literal}").as_bytes(), 0));
+        assert_eq!(None, re.find_at(b"axbbc(d)[e]{2}|^$\\", 0));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(4, Regex::new("(abc").unwrap_err().position);
+        assert!(Regex::new("abc)").is_err());
+        assert!(Regex::new("[abc").is_err());
+        assert!(Regex::new("*a").is_err());
+        assert!(Regex::new("[z-a]").is_err());
+        assert!(Regex::new(r"\q").is_err());
+        assert!(Regex::new("a{2000}").is_err());
+        assert!(Regex::new("a{,2}").is_ok());
+
+        // deep nesting is an error rather than a stack overflow
+        let nested = |open: &str, atom: &str, close: &str, n| open.repeat(n) + atom + &close.repeat(n);
+        assert!(Regex::new(&nested("(", "a", ")", MAX_NESTING)).is_ok());
+        assert!(Regex::new(&nested("(", "a", ")", MAX_NESTING + 1)).is_err());
+        assert!(Regex::new(&nested("(", "a", ")", 50_000)).is_err());
+        assert!(Regex::new(&nested("(", "", "", 50_000)).is_err());
+        assert!(Regex::new(&nested("", "a", "*", 50_000)).is_err());
+        assert!(Regex::new(&nested("(a", "b", ")*", 50_000)).is_err());
+        assert!(Regex::with_bounds(&nested("", "a", "?", MAX_NESTING), true, Bounds::Word).is_ok());
+    }
+}
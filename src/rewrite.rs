@@ -0,0 +1,173 @@
+//! Rewriting files on disk with every match replaced.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::matcher::Matcher;
+
+pub struct Rewrite<'a> {
+    pub matcher: &'a Matcher,
+    pub replacement: &'a str,
+    pub preserve_case: bool,
+    /// Keep a copy of the original next to it, named with this suffix.
+    pub backup_suffix: Option<&'a str>,
+    /// Rewrite files even if they look binary.
+    pub text: bool,
+}
+
+impl Rewrite<'_> {
+    /// Replaces every match in the file at `path` and returns how many
+    /// replacements were made. Files without a match are left untouched.
+    ///
+    /// The new contents are written to a temporary file in the same
+    /// directory that is then renamed over the original, so readers only
+    /// ever see the old or the new file. Permissions are preserved.
+    pub fn rewrite_file(&self, path: &Path) -> io::Result<usize> {
+        // write through symlinks instead of replacing them
+        let path = fs::canonicalize(path)?;
+        if !self.needs_rewrite(&path)? {
+            return Ok(0);
+        }
+
+        // only a temporary file made here is ever removed again
+        let temp = temp_path(&path);
+        let file = OpenOptions::new().write(true).create_new(true).open(&temp)?;
+        let result = self.write_replaced(&path, file, &temp);
+        let replacements = match result {
+            Ok(replacements) => replacements,
+            Err(err) => {
+                let _ = fs::remove_file(&temp);
+                return Err(err);
+            }
+        };
+
+        if let Some(suffix) = self.backup_suffix {
+            let mut backup = path.clone().into_os_string();
+            backup.push(suffix);
+            fs::copy(&path, backup).inspect_err(|_| {
+                let _ = fs::remove_file(&temp);
+            })?;
+        }
+        fs::rename(&temp, &path).inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })?;
+
+        Ok(replacements)
+    }
+
+    // cheap first pass so files without matches are never rewritten
+    fn needs_rewrite(&self, path: &Path) -> io::Result<bool> {
+        let mut reader = BufReader::new(File::open(path)?);
+        if !self.text && reader.fill_buf()?.contains(&0) {
+            return Ok(false);
+        }
+
+        let mut buf = Vec::new();
+        while reader.read_until(b'\n', &mut buf)? > 0 {
+            if self.matcher.is_match(line_of(&buf).0) {
+                return Ok(true);
+            }
+            buf.clear();
+        }
+
+        Ok(false)
+    }
+
+    fn write_replaced(&self, path: &Path, file: File, temp: &Path) -> io::Result<usize> {
+        let permissions = fs::metadata(path)?.permissions();
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut writer = BufWriter::new(file);
+        let mut buf = Vec::new();
+        let mut replaced = Vec::new();
+        let mut replacements = 0;
+
+        while reader.read_until(b'\n', &mut buf)? > 0 {
+            let (line, terminator) = line_of(&buf);
+            replaced.clear();
+            replacements += self.matcher.replace_into(line, self.replacement, self.preserve_case, &mut replaced);
+            writer.write_all(&replaced)?;
+            writer.write_all(terminator)?;
+            buf.clear();
+        }
+
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        fs::set_permissions(temp, permissions)?;
+
+        Ok(replacements)
+    }
+}
+
+// splits a line read with read_until into its contents and terminator
+fn line_of(buf: &[u8]) -> (&[u8], &[u8]) {
+    let len = if buf.ends_with(b"\r\n") {
+        buf.len() - 2
+    } else if buf.ends_with(b"\n") {
+        buf.len() - 1
+    } else {
+        buf.len()
+    };
+    buf.split_at(len)
+}
+
+// unique within the process as well, since the same file can be reached
+// twice at once, through repeated paths or symlinks
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(".{name}.kkjgrep-{}-{n}.tmp", process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regex::Bounds;
+
+    #[test]
+    fn rewrites_in_place_with_backup() {
+        let dir = std::env::temp_dir().join(format!("kkjgrep-rewrite-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.txt");
+        fs::write(&path, "Rust and rust\r\nno match\nRUST").unwrap();
+
+        let matcher = Matcher::new("rust", true, true, Bounds::Any).unwrap();
+        let rewrite = Rewrite {
+            matcher: &matcher,
+            replacement: "go",
+            preserve_case: true,
+            backup_suffix: Some(".bak"),
+            text: false,
+        };
+
+        assert_eq!(3, rewrite.rewrite_file(&path).unwrap());
+        assert_eq!("Go and go\r\nno match\nGO", fs::read_to_string(&path).unwrap());
+        assert_eq!("Rust and rust\r\nno match\nRUST", fs::read_to_string(dir.join("notes.txt.bak")).unwrap());
+
+        assert_eq!(0, rewrite.rewrite_file(&path).unwrap());
+        assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+
+        // rewriting the same file from several threads at once
+        fs::write(&path, "rust\n".repeat(1000)).unwrap();
+        let rewrite = Rewrite { backup_suffix: None, ..rewrite };
+        std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8).map(|_| scope.spawn(|| rewrite.rewrite_file(&path))).collect();
+            let replacements: usize = threads.into_iter().map(|thread| thread.join().unwrap().unwrap()).sum();
+            assert!(replacements >= 1000);
+        });
+        assert_eq!("go\n".repeat(1000), fs::read_to_string(&path).unwrap());
+        assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+
+        // a backup that can't be made leaves everything as it was
+        fs::write(&path, "rust").unwrap();
+        let rewrite = Rewrite { backup_suffix: Some("/bak"), ..rewrite };
+        assert!(rewrite.rewrite_file(&path).is_err());
+        assert_eq!("rust", fs::read_to_string(&path).unwrap());
+        assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
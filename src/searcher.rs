@@ -0,0 +1,411 @@
+//! Line-by-line search over a reader, so files of any size and standard
+//! input are searched in bounded memory, or over bytes already in memory.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead};
+use std::ops::ControlFlow;
+
+use crate::memchr::{self, Finder};
+use crate::{Line, Match, Span};
+
+/// What to do with input that looks binary, i.e. contains a NUL byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BinaryFiles {
+    /// Stop at the first match and only report that the input matches.
+    #[default]
+    Binary,
+    /// Treat the input as if nothing matched.
+    WithoutMatch,
+    /// Search and print it like any other text.
+    Text,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Searcher<'a> {
+    /// Lines of context to report before and after each match.
+    pub before_context: usize,
+    pub after_context: usize,
+    pub binary_files: BinaryFiles,
+    /// Stop reading as soon as this many lines have matched.
+    pub max_matches: Option<usize>,
+    /// Select the lines without a match instead of the ones with one.
+    pub invert_match: bool,
+    /// A string every matched line contains. The input is scanned for it a
+    /// buffer at a time, jumping from one occurrence to the next instead of
+    /// matching the lines between.
+    pub literal: Option<&'a Finder>,
+}
+
+/// What happened while searching one input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// Number of matched lines handed to the sink.
+    pub matched_lines: usize,
+    /// The input turned out to be binary and a match was found in it that
+    /// wasn't handed to the sink.
+    pub binary_match: bool,
+}
+
+/// Where the searched bytes come from.
+#[derive(Debug)]
+pub enum Input<'a, R> {
+    /// Read a line at a time, like a file or a pipe.
+    Reader(R),
+    /// Already in memory as a whole, like a memory-mapped file.
+    Slice(&'a [u8]),
+}
+
+// how much of the input is looked at up front to decide whether it's binary,
+// the same as a default sized `BufReader` fills in one read
+const BINARY_PROBE: usize = 8 * 1024;
+
+impl Searcher<'_> {
+    /// Searches `input`, either with [`Searcher::search_reader`] or with
+    /// [`Searcher::search_slice`].
+    pub fn search<R, F, S>(&self, input: Input<R>, find: F, sink: S) -> io::Result<Summary>
+    where
+        R: BufRead,
+        F: Fn(&[u8]) -> Option<Span>,
+        S: FnMut(Line) -> io::Result<()>,
+    {
+        match input {
+            Input::Reader(reader) => self.search_reader(reader, find, sink),
+            Input::Slice(haystack) => self.search_slice(haystack, find, sink),
+        }
+    }
+
+    /// Reads `reader` one line at a time and hands every matched line, plus
+    /// the configured context around it, to `sink`. `find` returns the first
+    /// match in a line, which is passed along with it.
+    ///
+    /// Only the current line and the last `before_context` lines are kept
+    /// in memory. Lines are matched as raw bytes, so the input doesn't have
+    /// to be UTF-8.
+    ///
+    /// With a `literal`, the reader's whole buffer is scanned for it and
+    /// only the lines around its occurrences are read out one by one.
+    pub fn search_reader<R, F, S>(&self, mut reader: R, find: F, mut sink: S) -> io::Result<Summary>
+    where
+        R: BufRead,
+        F: Fn(&[u8]) -> Option<Span>,
+        S: FnMut(Line) -> io::Result<()>,
+    {
+        let binary = self.binary_files != BinaryFiles::Text && reader.fill_buf()?.contains(&0);
+        let mut state = State::new(self, binary);
+        let mut buf = Vec::new();
+
+        loop {
+            if let Some(literal) = self.literal.filter(|_| !self.invert_match && state.after_left == 0) {
+                // only whole lines can be skipped, a line cut off at the end
+                // of the buffer is looked at again after the next read
+                let filled = reader.fill_buf()?;
+                let lines = filled.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+                let skip = self.skippable(literal, &filled[..lines]);
+                if skip > 0 {
+                    if state.skip(&filled[..skip]).is_break() {
+                        return Ok(state.summary);
+                    }
+                    reader.consume(skip);
+                    continue;
+                }
+            }
+
+            buf.clear();
+            if reader.read_until(b'\n', &mut buf)? == 0 || state.line(&buf, &find, &mut sink)?.is_break() {
+                return Ok(state.summary);
+            }
+        }
+    }
+
+    /// Like [`Searcher::search_reader`], but over bytes that are already in
+    /// memory, so lines are matched where they are instead of being copied.
+    pub fn search_slice<F, S>(&self, haystack: &[u8], find: F, mut sink: S) -> io::Result<Summary>
+    where
+        F: Fn(&[u8]) -> Option<Span>,
+        S: FnMut(Line) -> io::Result<()>,
+    {
+        let probe = &haystack[..haystack.len().min(BINARY_PROBE)];
+        let binary = self.binary_files != BinaryFiles::Text && probe.contains(&0);
+        let mut state = State::new(self, binary);
+        let mut pos = 0;
+
+        while pos < haystack.len() {
+            // lines selected with -v are the ones without the literal, and
+            // lines of after context have to be looked at one by one
+            if let Some(literal) = self.literal.filter(|_| !self.invert_match && state.after_left == 0) {
+                let rest = &haystack[pos..];
+                let skip = self.skippable(literal, rest);
+                if state.skip(&rest[..skip]).is_break() {
+                    break;
+                }
+                pos += skip;
+                if pos == haystack.len() {
+                    break;
+                }
+            }
+
+            let end = memchr::memchr(b'\n', &haystack[pos..]).map_or(haystack.len(), |i| pos + i + 1);
+            if state.line(&haystack[pos..end], &find, &mut sink)?.is_break() {
+                break;
+            }
+            pos = end;
+        }
+        Ok(state.summary)
+    }
+
+    // length of the lines at the start of `haystack` that can't match or be
+    // needed as context, since they come before the first line with `literal`
+    // and aren't among the `before_context` lines right before it
+    fn skippable(&self, literal: &Finder, haystack: &[u8]) -> usize {
+        let hit = literal.find(haystack).map_or(haystack.len(), |hit| line_start(haystack, hit));
+        keep_last_lines(&haystack[..hit], self.before_context)
+    }
+}
+
+// start of the line that contains `pos`
+fn line_start(haystack: &[u8], pos: usize) -> usize {
+    haystack[..pos].iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1)
+}
+
+// length of `lines` without its last `keep` lines, which have to be searched
+// one by one to serve as before context
+fn keep_last_lines(lines: &[u8], keep: usize) -> usize {
+    let mut end = lines.len();
+    for _ in 0..keep {
+        if end == 0 {
+            break;
+        }
+        end = line_start(lines, end - 1);
+    }
+    end
+}
+
+/// Where a search is at, carried from one line to the next.
+struct State<'s> {
+    searcher: &'s Searcher<'s>,
+    summary: Summary,
+    binary: bool,
+    line_number: usize,
+    byte_offset: usize,
+    // (line number, byte offset, line) of recent lines that weren't printed
+    pending: VecDeque<(usize, usize, Vec<u8>)>,
+    after_left: usize,
+    last_printed: Option<usize>,
+}
+
+impl<'s> State<'s> {
+    fn new(searcher: &'s Searcher<'s>, binary: bool) -> State<'s> {
+        State {
+            searcher,
+            summary: Summary::default(),
+            binary,
+            line_number: 0,
+            byte_offset: 0,
+            pending: VecDeque::new(),
+            after_left: 0,
+            last_printed: None,
+        }
+    }
+
+    /// Moves past whole lines that can't match without handing them to the
+    /// sink, and tells whether to go on.
+    fn skip(&mut self, lines: &[u8]) -> ControlFlow<()> {
+        let binary_files = self.searcher.binary_files;
+        self.line_number += lines.iter().filter(|&&b| b == b'\n').count();
+        self.byte_offset += lines.len();
+
+        self.binary = self.binary || binary_files != BinaryFiles::Text && lines.contains(&0);
+        if self.binary && binary_files == BinaryFiles::WithoutMatch {
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Handles one line, terminator included, and tells whether to go on.
+    fn line<F, S>(&mut self, raw: &[u8], find: &F, sink: &mut S) -> io::Result<ControlFlow<()>>
+    where
+        F: Fn(&[u8]) -> Option<Span>,
+        S: FnMut(Line) -> io::Result<()>,
+    {
+        let searcher = self.searcher;
+        let (before, after) = (searcher.before_context, searcher.after_context);
+        self.line_number += 1;
+        let line_number = self.line_number;
+
+        self.binary = self.binary || searcher.binary_files != BinaryFiles::Text && raw.contains(&0);
+        if self.binary && searcher.binary_files == BinaryFiles::WithoutMatch {
+            return Ok(ControlFlow::Break(()));
+        }
+
+        let line = raw.strip_suffix(b"\n").unwrap_or(raw);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let span = find(line);
+        let mut m = Match { line_number, byte_offset: self.byte_offset, line, span: None };
+        self.byte_offset += raw.len();
+
+        if span.is_some() != searcher.invert_match {
+            m.span = span;
+            if self.binary {
+                self.summary.binary_match = true;
+                return Ok(ControlFlow::Break(()));
+            }
+            self.summary.matched_lines += 1;
+
+            let first = self.pending.front().map_or(line_number, |&(n, _, _)| n);
+            let context = before > 0 || after > 0;
+            if context && self.last_printed.is_some_and(|last| first > last + 1) {
+                sink(Line::Break)?;
+            }
+            for (line_number, byte_offset, line) in self.pending.drain(..) {
+                sink(Line::Context(Match { line_number, byte_offset, line: &line, span: None }))?;
+            }
+            sink(Line::Matched(m))?;
+            if searcher.max_matches == Some(self.summary.matched_lines) {
+                return Ok(ControlFlow::Break(()));
+            }
+            self.after_left = after;
+            self.last_printed = Some(line_number);
+        } else if self.after_left > 0 && !self.binary {
+            sink(Line::Context(m))?;
+            self.after_left -= 1;
+            self.last_printed = Some(line_number);
+        } else if before > 0 {
+            if self.pending.len() == before {
+                self.pending.pop_front();
+            }
+            self.pending.push_back((line_number, m.byte_offset, line.to_vec()));
+        }
+
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // searches both as a reader and as a slice, which have to agree
+    fn render(searcher: Searcher, contents: &[u8]) -> (Vec<String>, Summary) {
+        let read = collect(searcher, Input::Reader(contents));
+        assert_eq!(read, collect(searcher, Input::<&[u8]>::Slice(contents)));
+        read
+    }
+
+    fn collect<R: BufRead>(searcher: Searcher, input: Input<R>) -> (Vec<String>, Summary) {
+        let mut out = Vec::new();
+        let find = |line: &[u8]| line.starts_with(b"match").then_some(Span { start: 0, end: 5 });
+        let summary = searcher
+            .search(input, find, |line| {
+                out.push(match line {
+                    Line::Matched(m) => format!("{}:{}", m.line_number, m.byte_offset),
+                    Line::Context(m) => format!("{}-{}", m.line_number, m.byte_offset),
+                    Line::Break => "--".to_string(),
+                });
+                Ok(())
+            })
+            .unwrap();
+        (out, summary)
+    }
+
+    #[test]
+    fn streams_matches_with_context() {
+        let contents = b"1\nmatch 2\n3\n4\nmatch 5\n6\n7\n8\n9\nmatch 10\n11\n";
+        let context = Searcher { before_context: 1, after_context: 2, ..Searcher::default() };
+
+        assert_eq!(vec!["2:2", "5:14", "10:30"], render(Searcher::default(), contents).0);
+        assert_eq!(
+            vec!["1-0", "2:2", "3-10", "4-12", "5:14", "6-22", "7-24", "--", "9-28", "10:30", "11-39"],
+            render(context, contents).0
+        );
+    }
+
+    #[test]
+    fn huge_context() {
+        let searcher = Searcher { before_context: usize::MAX, after_context: usize::MAX, ..Searcher::default() };
+
+        assert_eq!(vec!["1-0", "2:2", "3-10"], render(searcher, b"1\nmatch 2\n3\n").0);
+    }
+
+    #[test]
+    fn invalid_utf8_is_searched_as_bytes() {
+        let (lines, summary) = render(Searcher::default(), b"match caf\xe9\nplain\n");
+
+        assert_eq!(vec!["1:0"], lines);
+        assert_eq!(Summary { matched_lines: 1, binary_match: false }, summary);
+    }
+
+    #[test]
+    fn binary_files() {
+        let contents = b"match 1\nmatch 2\n\0\nmatch 4\n";
+        let binary = |binary_files| Searcher { binary_files, ..Searcher::default() };
+
+        // the NUL shows up in the first buffer, so nothing is printed
+        let (lines, summary) = render(binary(BinaryFiles::Binary), contents);
+        assert!(lines.is_empty());
+        assert_eq!(Summary { matched_lines: 0, binary_match: true }, summary);
+
+        let (lines, summary) = render(binary(BinaryFiles::WithoutMatch), contents);
+        assert!(lines.is_empty());
+        assert_eq!(Summary::default(), summary);
+
+        let (lines, summary) = render(binary(BinaryFiles::Text), contents);
+        assert_eq!(vec!["1:0", "2:8", "4:18"], lines);
+        assert_eq!(Summary { matched_lines: 3, binary_match: false }, summary);
+    }
+
+    #[test]
+    fn invert_match() {
+        let searcher = Searcher { invert_match: true, before_context: 1, ..Searcher::default() };
+        let (lines, summary) = render(searcher, b"match 1\n2\nmatch 3\nmatch 4\n5\n");
+
+        assert_eq!(vec!["1-0", "2:8", "--", "4-18", "5:26"], lines);
+        assert_eq!(2, summary.matched_lines);
+    }
+
+    #[test]
+    fn stops_at_max_matches() {
+        let searcher = Searcher { max_matches: Some(1), after_context: 1, ..Searcher::default() };
+        let (lines, summary) = render(searcher, b"1
+match 2
+3
+match 4
+");
+
+        assert_eq!(vec!["2:2"], lines);
+        assert_eq!(1, summary.matched_lines);
+    }
+
+    #[test]
+    fn skips_to_literal() {
+        // both a reader and a slice jump between occurrences, so the lines
+        // they find are spelled out
+        let literal = Finder::new(b"match");
+        let contents = b"1\nmatch 2\n3 match\n4\n5\n6\nmatch 7\nmatch 8\n9\n10\n11\nmatch 12";
+        let searcher = |before_context, after_context| Searcher {
+            before_context,
+            after_context,
+            literal: Some(&literal),
+            ..Searcher::default()
+        };
+
+        assert_eq!(vec!["2:2", "7:24", "8:32", "12:48"], render(searcher(0, 0), contents).0);
+        assert_eq!(
+            vec!["1-0", "2:2", "3-10", "--", "6-22", "7:24", "8:32", "9-40", "--", "11-45", "12:48"],
+            render(searcher(1, 1), contents).0
+        );
+        assert_eq!(12, render(searcher(5, 0), contents).0.len());
+
+        // lines and occurrences cut off at the end of the reader's buffer
+        for capacity in [1, 4, 7, 16] {
+            for (before, after) in [(0, 0), (1, 1), (5, 0)] {
+                let reader = io::BufReader::with_capacity(capacity, &contents[..]);
+                let read = collect(searcher(before, after), Input::Reader(reader));
+                assert_eq!(render(searcher(before, after), contents), read);
+            }
+        }
+
+        let (lines, summary) = render(searcher(0, 0), b"1\n\0\nmatch 3\n");
+        assert!(lines.is_empty());
+        assert!(summary.binary_match);
+    }
+}
@@ -0,0 +1,233 @@
+//! Recursive, deterministically ordered traversal of the searched paths.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::ignore::{self, Gitignore};
+
+/// Yields every regular file below the given paths, depth first with
+/// directory entries sorted by name.
+///
+/// Paths named explicitly are always followed and yielded even if they
+/// aren't regular files or are ignored; symlinks found while walking are
+/// skipped unless `follow_links` is set.
+pub struct Walk {
+    stack: Vec<(PathBuf, usize)>,
+    follow_links: bool,
+    ignore: bool,
+    // canonical paths of the directories above the current entry, used to
+    // detect symlink loops
+    ancestors: Vec<PathBuf>,
+    root: PathBuf,
+    // where the current root sits in its repository, and the rules
+    // inherited from the directories above it
+    root_prefix: String,
+    root_rules: Gitignore,
+    // rules read from each directory above the current entry
+    rules: Vec<Gitignore>,
+}
+
+impl Walk {
+    pub fn new<P: AsRef<Path>>(paths: &[P]) -> Walk {
+        let stack = paths
+            .iter()
+            .rev()
+            .map(|path| (path.as_ref().to_path_buf(), 0))
+            .collect();
+
+        Walk {
+            stack,
+            follow_links: false,
+            ignore: true,
+            ancestors: Vec::new(),
+            root: PathBuf::new(),
+            root_prefix: String::new(),
+            root_rules: Gitignore::default(),
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn follow_links(mut self, yes: bool) -> Walk {
+        self.follow_links = yes;
+        self
+    }
+
+    /// Whether to honor ignore files; on by default.
+    pub fn ignore(mut self, yes: bool) -> Walk {
+        self.ignore = yes;
+        self
+    }
+
+    fn start_root(&mut self, root: &Path) {
+        self.root = root.to_path_buf();
+        self.rules.clear();
+        self.root_prefix.clear();
+        self.root_rules = Gitignore::default();
+        if self.ignore && root.is_dir() {
+            (self.root_prefix, self.root_rules) = Gitignore::for_root(root);
+        }
+    }
+
+    // path relative to the repository root, as ignore rules expect it
+    fn relative(&self, path: &Path) -> String {
+        let rest = path.strip_prefix(&self.root).unwrap_or(path);
+        ignore::join(&self.root_prefix, &ignore::relative(rest))
+    }
+
+    fn is_ignored(&self, path: &Path, depth: usize, is_dir: bool) -> bool {
+        if path.file_name().is_some_and(|name| name == ".git") {
+            return true;
+        }
+
+        let relative = self.relative(path);
+        self.rules[..depth]
+            .iter()
+            .rev()
+            .chain([&self.root_rules])
+            .find_map(|rules| rules.matched(&relative, is_dir))
+            .unwrap_or(false)
+    }
+
+    fn push_dir(&mut self, dir: &Path, depth: usize) -> io::Result<()> {
+        if self.follow_links {
+            let canonical = fs::canonicalize(dir).map_err(|err| with_path(dir, err))?;
+            self.ancestors.truncate(depth);
+            if self.ancestors.contains(&canonical) {
+                return Err(io::Error::other(format!(
+                    "{}: filesystem loop detected",
+                    dir.display()
+                )));
+            }
+            self.ancestors.push(canonical);
+        }
+
+        let mut entries = fs::read_dir(dir)
+            .and_then(|entries| entries.map(|entry| Ok(entry?.path())).collect::<io::Result<Vec<_>>>())
+            .map_err(|err| with_path(dir, err))?;
+        entries.sort();
+
+        if self.ignore {
+            self.rules.truncate(depth);
+            self.rules.push(Gitignore::from_dir(dir, &self.relative(dir)));
+        }
+
+        self.stack.extend(entries.into_iter().rev().map(|path| (path, depth + 1)));
+
+        Ok(())
+    }
+}
+
+impl Iterator for Walk {
+    type Item = io::Result<PathBuf>;
+
+    fn next(&mut self) -> Option<io::Result<PathBuf>> {
+        while let Some((path, depth)) = self.stack.pop() {
+            let root = depth == 0;
+            if root {
+                self.start_root(&path);
+            }
+            let metadata = if root || self.follow_links {
+                fs::metadata(&path)
+            } else {
+                fs::symlink_metadata(&path)
+            };
+            let metadata = match metadata {
+                Ok(metadata) => metadata,
+                Err(err) => return Some(Err(with_path(&path, err))),
+            };
+
+            if !root && self.ignore && self.is_ignored(&path, depth, metadata.is_dir()) {
+                continue;
+            }
+
+            if metadata.is_dir() {
+                if let Err(err) = self.push_dir(&path, depth) {
+                    return Some(Err(err));
+                }
+            } else if root || metadata.is_file() {
+                return Some(Ok(path));
+            }
+        }
+
+        None
+    }
+}
+
+pub(crate) fn with_path(path: &Path, err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("kkjgrep-walk-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("b/inner")).unwrap();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("b/inner/z.txt"), "").unwrap();
+        fs::write(root.join("b/c.txt"), "").unwrap();
+        fs::write(root.join("a/d.txt"), "").unwrap();
+        fs::write(root.join("top.txt"), "").unwrap();
+        root
+    }
+
+    fn relative(root: &Path, walk: Walk) -> Vec<String> {
+        walk.map(|path| path.unwrap().strip_prefix(root).unwrap().display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn sorted_depth_first() {
+        let root = tree("sorted");
+
+        assert_eq!(
+            vec!["a/d.txt", "b/c.txt", "b/inner/z.txt", "top.txt"],
+            relative(&root, Walk::new(&[&root]).ignore(false))
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_opt_in() {
+        let root = tree("symlinks");
+        std::os::unix::fs::symlink(root.join("a"), root.join("b/link")).unwrap();
+        std::os::unix::fs::symlink(&root, root.join("a/loop")).unwrap();
+
+        assert_eq!(
+            vec!["a/d.txt", "b/c.txt", "b/inner/z.txt", "top.txt"],
+            relative(&root, Walk::new(&[&root]).ignore(false))
+        );
+
+        let followed: Vec<_> = Walk::new(&[&root]).ignore(false).follow_links(true).collect();
+        assert_eq!(2, followed.iter().filter(|entry| entry.is_err()).count());
+        assert_eq!(
+            5,
+            followed.iter().filter(|entry| entry.is_ok()).count()
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ignore_files() {
+        let root = tree("ignore");
+        fs::create_dir_all(root.join(".git/info")).unwrap();
+        fs::write(root.join(".git/info/exclude"), "top.txt\n").unwrap();
+        fs::write(root.join(".gitignore"), "inner/\n*.txt\n!c.txt\n").unwrap();
+        fs::write(root.join("b/.kkjgrepignore"), "c.txt\n").unwrap();
+        fs::write(root.join("a/.ignore"), "!d.txt\n").unwrap();
+
+        assert_eq!(
+            vec![".gitignore", "a/.ignore", "a/d.txt", "b/.kkjgrepignore"],
+            relative(&root, Walk::new(&[&root]))
+        );
+        assert_eq!(
+            vec!["b/.kkjgrepignore"],
+            relative(&root, Walk::new(&[root.join("b")]))
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}